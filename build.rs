@@ -1,6 +1,13 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=./proto/nauthz.proto");
+    println!("cargo:rerun-if-changed=./proto/admin.proto");
+
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(&["./proto/nauthz.proto"], &["../../proto"])?;
+        .compile(
+            &["./proto/nauthz.proto", "./proto/admin.proto"],
+            &["../../proto", "./proto"],
+        )?;
     Ok(())
 }
@@ -0,0 +1,274 @@
+//! gRPC service to manage users, authenticated with the http api keys
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use nostr_sdk::key::XOnlyPublicKey;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::auth::Scope;
+use crate::nauthz_admin::user_admin_server::UserAdmin;
+use crate::nauthz_admin::{
+    self, GetStatusRequest, ListUsersRequest, UpdateUsersReply, UpdateUsersRequest, User,
+    WatchChangesRequest,
+};
+use crate::repo::{Actor, Change, ChangeSource, ListDiff, Repo};
+use crate::{utils, UserStatus};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Users read per lock of the repo while streaming `ListUsers`
+const LIST_USERS_PAGE: usize = 500;
+
+pub struct Admin {
+    pub repo: Arc<Mutex<Repo>>,
+}
+
+impl From<UserStatus> for nauthz_admin::UserStatus {
+    fn from(status: UserStatus) -> Self {
+        match status {
+            UserStatus::Allowed => Self::Allowed,
+            UserStatus::Denied => Self::Denied,
+            UserStatus::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl From<ListDiff> for nauthz_admin::ListDiff {
+    fn from(diff: ListDiff) -> Self {
+        Self {
+            added: diff.added.iter().map(|p| p.to_string()).collect(),
+            removed: diff.removed.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl From<Change> for nauthz_admin::Change {
+    fn from(change: Change) -> Self {
+        Self {
+            id: change.id,
+            created_at: change.created_at,
+            actor: change.actor.to_string(),
+            source: change.source.to_string(),
+            allow: Some(change.allow.into()),
+            deny: Some(change.deny.into()),
+        }
+    }
+}
+
+impl Admin {
+    /// Check the `authorization: Bearer` api key against `scope`, returns the key as the actor
+    async fn authorize<T>(&self, request: &Request<T>, scope: Scope) -> Result<Actor, Status> {
+        let secret = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(Status::unauthenticated("No api key"))?;
+
+        let key = self
+            .repo
+            .lock()
+            .await
+            .find_api_key(secret)
+            .ok_or(Status::unauthenticated("Invalid api key"))?;
+
+        if key.scope < scope {
+            return Err(Status::permission_denied(format!(
+                "Api key {} does not have the {scope:?} scope",
+                key.id
+            )));
+        }
+
+        Ok(Actor::ApiKey(key.id))
+    }
+
+    // `tonic::Status` is the error type of every rpc
+    #[allow(clippy::result_large_err)]
+    fn pubkeys(request: &UpdateUsersRequest) -> Result<HashSet<XOnlyPublicKey>, Status> {
+        request
+            .pubkeys
+            .iter()
+            .map(|p| {
+                utils::parse_pubkey(p).map_err(|err| Status::invalid_argument(err.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn user(repo: &Repo, pubkey: XOnlyPublicKey, status: UserStatus) -> User {
+    let record = repo.get_record(&pubkey);
+
+    User {
+        pubkey: pubkey.to_string(),
+        status: nauthz_admin::UserStatus::from(status) as i32,
+        created_at: record.as_ref().map(|r| r.created_at),
+        updated_at: record.as_ref().map(|r| r.updated_at),
+        updated_by: record.as_ref().map(|r| r.updated_by.to_string()),
+        expires_at: repo
+            .get_deny_expiry(&pubkey)
+            .filter(|_| status.eq(&UserStatus::Denied)),
+        note: record.as_ref().and_then(|r| r.note.clone()),
+        labels: record
+            .map(|r| r.labels.into_iter().collect())
+            .unwrap_or_default(),
+    }
+}
+
+#[tonic::async_trait]
+impl UserAdmin for Admin {
+    async fn allow(
+        &self,
+        request: Request<UpdateUsersRequest>,
+    ) -> Result<Response<UpdateUsersReply>, Status> {
+        let actor = self.authorize(&request, Scope::Admin).await?;
+        let pubkeys = Self::pubkeys(request.get_ref())?;
+
+        let mut repo = self.repo.lock().await;
+        repo.admit_pubkeys(&pubkeys, &actor, ChangeSource::Grpc)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if let Some(note) = &request.get_ref().note {
+            repo.set_note(&pubkeys, note);
+            repo.save_records()
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+        }
+
+        Ok(Response::new(UpdateUsersReply {}))
+    }
+
+    async fn deny(
+        &self,
+        request: Request<UpdateUsersRequest>,
+    ) -> Result<Response<UpdateUsersReply>, Status> {
+        let actor = self.authorize(&request, Scope::Deny).await?;
+        let pubkeys = Self::pubkeys(request.get_ref())?;
+
+        let mut repo = self.repo.lock().await;
+        repo.deny_pubkeys(&pubkeys, &actor, ChangeSource::Grpc)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if let Some(note) = &request.get_ref().note {
+            repo.set_note(&pubkeys, note);
+            repo.save_records()
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+        }
+
+        Ok(Response::new(UpdateUsersReply {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<UpdateUsersRequest>,
+    ) -> Result<Response<UpdateUsersReply>, Status> {
+        let actor = self.authorize(&request, Scope::Admin).await?;
+        let pubkeys = Self::pubkeys(request.get_ref())?;
+
+        self.repo
+            .lock()
+            .await
+            .remove_pubkeys(&pubkeys, &actor, ChangeSource::Grpc)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(UpdateUsersReply {}))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<GetStatusRequest>,
+    ) -> Result<Response<User>, Status> {
+        self.authorize(&request, Scope::Read).await?;
+        let pubkey = utils::parse_pubkey(&request.get_ref().pubkey)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let repo = self.repo.lock().await;
+        let status = repo.get_user_status(pubkey).await;
+
+        Ok(Response::new(user(&repo, pubkey, status)))
+    }
+
+    type ListUsersStream = ResponseStream<User>;
+
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<Self::ListUsersStream>, Status> {
+        self.authorize(&request, Scope::Read).await?;
+        let request = request.into_inner();
+        let status = match request.status.map(nauthz_admin::UserStatus::from_i32) {
+            Some(Some(nauthz_admin::UserStatus::Allowed)) => Some(UserStatus::Allowed),
+            Some(Some(nauthz_admin::UserStatus::Denied)) => Some(UserStatus::Denied),
+            Some(_) => return Err(Status::invalid_argument("Status must be allowed or denied")),
+            None => None,
+        };
+
+        // Read a page at a time so the repo is not locked for the whole list
+        let repo = self.repo.clone();
+        let (sender, receiver) = mpsc::channel(LIST_USERS_PAGE);
+        tokio::spawn(async move {
+            let mut cursor = None;
+            loop {
+                let (users, next) = {
+                    let repo = repo.lock().await;
+                    let (entries, next) = repo.list_users(
+                        status,
+                        request.prefix.as_deref(),
+                        cursor,
+                        false,
+                        LIST_USERS_PAGE,
+                    );
+                    let users: Vec<User> = entries
+                        .into_iter()
+                        .map(|e| user(&repo, e.pubkey, e.status))
+                        .collect();
+                    (users, next)
+                };
+
+                for user in users {
+                    if sender.send(Ok(user)).await.is_err() {
+                        return;
+                    }
+                }
+
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => return,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    type WatchChangesStream = ResponseStream<nauthz_admin::Change>;
+
+    // The streamed items carry a `tonic::Status` as their error
+    #[allow(clippy::result_large_err)]
+    async fn watch_changes(
+        &self,
+        request: Request<WatchChangesRequest>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        self.authorize(&request, Scope::Read).await?;
+        let after_id = request.get_ref().after_id.unwrap_or(u64::MAX);
+
+        let (logged, receiver) = self.repo.lock().await.subscribe_changes(after_id);
+
+        let replay = tokio_stream::iter(logged.into_iter().map(|c| Ok(c.into())));
+        let live = BroadcastStream::new(receiver).map(|change| match change {
+            Ok(change) => Ok(change.into()),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => Err(Status::data_loss(format!(
+                "Missed {skipped} changes, watch again after the last received id"
+            ))),
+        });
+
+        Ok(Response::new(Box::pin(replay.chain(live))))
+    }
+}
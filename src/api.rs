@@ -0,0 +1,1248 @@
+//! HTTP api to manage relay users
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::body::{Body, HttpBody};
+use axum::extract::FromRequestParts;
+use axum::http::header::{AUTHORIZATION, HOST};
+use axum::http::request::Parts;
+use axum::http::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::{
+    async_trait,
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use nostr_sdk::key::XOnlyPublicKey;
+use nostr_sdk::prelude::ToBech32;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::audit::DecisionRecord;
+use crate::auth::{verify_nip98, ApiKey, HttpRequest, Scope};
+use crate::content::{ContentFilter, ContentRule};
+use crate::health::{Health, Readiness};
+use crate::kinds::KindRule;
+use crate::nauthz_grpc::Decision;
+use crate::repo::{Actor, Change, ChangeSource, Repo, UserCursor};
+use crate::tls::TlsFiles;
+use crate::{utils, EventAuthz, UserStatus};
+
+mod nip86;
+
+#[derive(Clone)]
+pub struct AppState {
+    repo: Arc<Mutex<Repo>>,
+    authz: EventAuthz,
+    health: Arc<Health>,
+}
+
+pub async fn start_server(
+    host: &str,
+    port: u16,
+    repo: Arc<Mutex<Repo>>,
+    authz: EventAuthz,
+    health: Arc<Health>,
+    tls: Option<TlsFiles>,
+    management: bool,
+) -> anyhow::Result<()> {
+    let shared_state = AppState {
+        repo,
+        authz,
+        health,
+    };
+
+    // Health checks and metrics are served even when the management api is disabled
+    let mut app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(get_metrics));
+    if management {
+        app = app.merge(management_routes(shared_state.clone()));
+    }
+    let app = app.with_state(shared_state);
+
+    let server_add = format!("{}:{}", host, port).parse()?;
+
+    match tls {
+        Some(files) => {
+            const ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+            let config = RustlsConfig::from_config(Arc::new(files.server_config(ALPN)?));
+            let reload = config.clone();
+            files.watch(ALPN, move |reloaded| reload.reload_from_config(reloaded));
+
+            axum_server::bind_rustls(server_add, config)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            // run it with hyper on localhost:3000
+            axum::Server::bind(&server_add)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Routes managing the lists, rules and api keys, authenticated with api keys or NIP-98
+fn management_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/", post(nip86::handle))
+        .route("/update", post(update_users))
+        .route("/users", get(get_users))
+        .route("/users/:pubkey", get(get_user).patch(update_user))
+        .route("/changes", get(get_changes))
+        .route("/decisions", get(get_decisions))
+        .route("/changes/:id/revert", post(revert_change))
+        .route("/keys", get(get_api_keys).post(create_api_key))
+        .route("/keys/:id", delete(revoke_api_key))
+        .route("/keys/:id/rotate", post(rotate_api_key))
+        .route("/invites", get(get_invites))
+        .route("/vouches", get(get_vouches))
+        .route(
+            "/rules/content",
+            get(get_content_rules).post(update_content_rule),
+        )
+        .route("/rules/content/:name", delete(delete_content_rule))
+        .route(
+            "/blocklist",
+            get(get_blocklist)
+                .post(update_blocklist)
+                .delete(delete_from_blocklist),
+        )
+        .route("/rules/kinds", get(get_kind_rules).post(update_kind_rule))
+        .route("/rules/kinds/:name", delete(delete_kind_rule))
+        .layer(middleware::from_fn_with_state(state, nip98_auth))
+}
+
+/// Liveness, the process is serving requests
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness, available once the lists are restored, with the status of each relay
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.health.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness))
+}
+
+/// Prometheus metrics, the list sizes are read when scraped
+async fn get_metrics(State(state): State<AppState>) -> Result<String, (StatusCode, String)> {
+    // Keep the previous list sizes rather than stall the scrape while the lists are restored
+    if let Ok(repo) = state.repo.try_lock() {
+        state
+            .authz
+            .metrics
+            .set_users(repo.allowed_pubkeys.len(), repo.denied_pubkeys.len());
+    }
+
+    state
+        .authz
+        .metrics
+        .encode()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Largest request body read to check the payload of a NIP-98 auth event
+const MAX_NIP98_BODY: usize = 2 * 1024 * 1024;
+
+/// Pubkey that signed the NIP-98 auth event of a request
+#[derive(Debug, Clone, Copy)]
+struct Nip98Signer(XOnlyPublicKey);
+
+/// Verify NIP-98 `Authorization: Nostr` headers, admitting requests signed by the relay key or an admin
+async fn nip98_auth(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, String)> {
+    let info = &state.authz.settings.info;
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| h.starts_with("Nostr "))
+        .map(str::to_string);
+
+    let header = match header {
+        Some(header) if info.nip98_auth => header,
+        _ => return Ok(next.run(request).await),
+    };
+
+    let (mut parts, mut body) = request.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_NIP98_BODY {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body too large".to_string(),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let request = HttpRequest {
+        host: parts
+            .headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default(),
+        path_and_query: parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/"),
+        method: parts.method.as_str(),
+        body: &bytes,
+    };
+    let signer = verify_nip98(&header, &request, info.nip98_max_age.unwrap_or(60))
+        .map_err(|err| (StatusCode::UNAUTHORIZED, err.to_string()))?;
+
+    if !signer.eq(&state.authz.pubkey) && !info.nip98_admins.contains(&signer) {
+        return Err((StatusCode::FORBIDDEN, "Signer is not an admin".to_string()));
+    }
+
+    parts.extensions.insert(Nip98Signer(signer));
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+/// Credentials of a request, an api key or a NIP-98 signer with the `admin` scope
+pub struct Auth {
+    actor: Actor,
+    scope: Scope,
+}
+
+impl Auth {
+    /// Actor of the changes made by the request if it has at least `scope`
+    fn require(&self, scope: Scope) -> Result<Actor, (StatusCode, String)> {
+        if self.scope < scope {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("{:?} does not have the {scope:?} scope", self.actor),
+            ));
+        }
+
+        Ok(self.actor.clone())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Auth {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(Nip98Signer(pubkey)) = parts.extensions.get::<Nip98Signer>() {
+            return Ok(Auth {
+                actor: Actor::Pubkey(*pubkey),
+                scope: Scope::Admin,
+            });
+        }
+
+        let secret = parts
+            .headers
+            .get("X-Api-Key")
+            .ok_or((StatusCode::UNAUTHORIZED, "No Api Key".to_string()))?
+            .to_str()
+            .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid API Key".to_string()))?;
+
+        let key = state
+            .repo
+            .lock()
+            .await
+            .find_api_key(secret)
+            .ok_or((StatusCode::UNAUTHORIZED, "Invalid API Key".to_string()))?;
+
+        Ok(Auth {
+            actor: Actor::ApiKey(key.id),
+            scope: key.scope,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Users {
+    #[serde(default, deserialize_with = "utils::deserialize_optional_pubkeys")]
+    pub allow: Option<HashSet<XOnlyPublicKey>>,
+    #[serde(default, deserialize_with = "utils::deserialize_optional_pubkeys")]
+    pub deny: Option<HashSet<XOnlyPublicKey>>,
+}
+
+/// Encoding of pubkeys in responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PubkeyFormat {
+    #[default]
+    Hex,
+    Npub,
+}
+
+impl PubkeyFormat {
+    pub fn encode(&self, pubkey: &XOnlyPublicKey) -> String {
+        match self {
+            Self::Hex => pubkey.to_string(),
+            Self::Npub => pubkey.to_bech32().unwrap_or(pubkey.to_string()),
+        }
+    }
+}
+
+/// Order of a page of users by the time they were added
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    #[serde(default)]
+    format: PubkeyFormat,
+    /// Only list `allowed` or `denied` pubkeys
+    status: Option<UserStatus>,
+    /// Hex or `npub` prefix of the listed pubkeys
+    prefix: Option<String>,
+    /// `next` cursor of the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+    order: Option<SortOrder>,
+}
+
+impl UsersQuery {
+    /// Whether any pagination, filter or sort parameter is set
+    fn is_paginated(&self) -> bool {
+        self.status.is_some()
+            || self.prefix.is_some()
+            || self.cursor.is_some()
+            || self.limit.is_some()
+            || self.order.is_some()
+    }
+}
+
+/// Allowed and denied pubkeys encoded in the requested format
+#[derive(Debug, Serialize)]
+pub struct UserList {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserListEntry {
+    pub pubkey: String,
+    pub status: UserStatus,
+    pub added_at: u64,
+}
+
+/// Page of users with the cursor of the next page
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    pub users: Vec<UserListEntry>,
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum UsersResponse {
+    List(UserList),
+    Page(UserPage),
+}
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+fn encode_cursor((added_at, pubkey): UserCursor) -> String {
+    format!("{added_at}:{pubkey}")
+}
+
+fn decode_cursor(cursor: &str) -> Option<UserCursor> {
+    let (added_at, pubkey) = cursor.split_once(':')?;
+
+    Some((
+        added_at.parse().ok()?,
+        XOnlyPublicKey::from_str(pubkey).ok()?,
+    ))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Blocklist {
+    /// Hex ids of blocked events
+    pub events: HashSet<String>,
+    /// Hex SHA256 of the normalized content of blocked events
+    pub hashes: HashSet<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateBlocklist {
+    #[serde(default)]
+    events: HashSet<String>,
+    #[serde(default)]
+    hashes: HashSet<String>,
+    /// Contents whose normalized hashes are added to or removed from `hashes`
+    #[serde(default)]
+    contents: Vec<String>,
+}
+
+impl UpdateBlocklist {
+    /// Event ids and hashes to update, rejecting any that is not 32 bytes of hex
+    fn parse(&self) -> Result<(HashSet<String>, HashSet<String>), (StatusCode, String)> {
+        let parse = |values: &HashSet<String>| {
+            values
+                .iter()
+                .map(|v| utils::parse_hex32(v))
+                .collect::<anyhow::Result<HashSet<String>>>()
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+        };
+        let events = parse(&self.events)?;
+        let mut hashes = parse(&self.hashes)?;
+        hashes.extend(self.contents.iter().map(|c| utils::content_hash(c)));
+
+        Ok((events, hashes))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUsers {
+    #[serde(default, deserialize_with = "utils::deserialize_optional_pubkeys")]
+    allow: Option<HashSet<XOnlyPublicKey>>,
+    #[serde(default, deserialize_with = "utils::deserialize_optional_pubkeys")]
+    deny: Option<HashSet<XOnlyPublicKey>>,
+    /// Remove from both lists, making the pubkeys unknown
+    #[serde(default, deserialize_with = "utils::deserialize_optional_pubkeys")]
+    remove: Option<HashSet<XOnlyPublicKey>>,
+    /// Also deny every pubkey invited by the denied pubkeys
+    #[serde(default)]
+    revoke_invites: bool,
+    /// Note attached to every pubkey in the update
+    note: Option<String>,
+    /// Labels replacing those of every pubkey in the update
+    labels: Option<BTreeSet<String>>,
+}
+
+/// Metadata of a single pubkey, fields that are not set are left unchanged
+#[derive(Debug, Deserialize)]
+pub struct UpdateUser {
+    /// Reason for the status of the pubkey, an empty note clears it
+    note: Option<String>,
+    labels: Option<BTreeSet<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKey {
+    id: String,
+    scope: Scope,
+    /// Unix time after which the key is rejected
+    expires_at: Option<u64>,
+}
+
+/// Api key with its secret, which is only returned when it is created or rotated
+#[derive(Debug, Serialize)]
+pub struct ApiKeySecret {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DecisionsQuery {
+    /// Only return decisions on events authored or sent by this pubkey
+    pubkey: Option<String>,
+    /// Only return decisions made at or after this unix time
+    #[serde(default)]
+    since: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    /// Only return changes made at or after this unix time
+    #[serde(default)]
+    since: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserQuery {
+    /// Kind of the event the decision is previewed for, defaults to 1
+    kind: Option<u64>,
+}
+
+/// Decision `event_admit` would make for an event
+#[derive(Debug, Serialize)]
+pub struct DecisionPreview {
+    pub kind: u64,
+    pub permit: bool,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    pub pubkey: XOnlyPublicKey,
+    pub npub: String,
+    pub status: UserStatus,
+    /// Unix time the pubkey was first added to a list
+    pub created_at: Option<u64>,
+    /// Unix time the status was last changed
+    pub updated_at: Option<u64>,
+    pub updated_by: Option<Actor>,
+    /// Unix time a time limited denial expires
+    pub expires_at: Option<u64>,
+    pub note: Option<String>,
+    pub labels: BTreeSet<String>,
+    pub decision: DecisionPreview,
+}
+
+async fn update_users(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(payload): Json<UpdateUsers>,
+) -> Result<(), (StatusCode, String)> {
+    debug!("Users: {payload:?}");
+    let scope = match (&payload.allow, &payload.remove) {
+        (None, None) => Scope::Deny,
+        _ => Scope::Admin,
+    };
+    let actor = auth.require(scope)?;
+
+    // Admit pubkeys
+    if let Some(pubkeys) = &payload.allow {
+        debug!("Pubkeys to allow: {pubkeys:?}");
+        state
+            .repo
+            .lock()
+            .await
+            .admit_pubkeys(pubkeys, &actor, ChangeSource::Http)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Could not get admitted pubkeys".to_string(),
+                )
+            })?;
+    }
+
+    // Deny pubkeys
+    if let Some(pubkeys) = &payload.deny {
+        let mut repo = state.repo.lock().await;
+        let mut pubkeys = pubkeys.clone();
+        if payload.revoke_invites {
+            let invited: HashSet<XOnlyPublicKey> = pubkeys
+                .iter()
+                .flat_map(|p| repo.invite_subtree(p))
+                .collect();
+            pubkeys.extend(invited);
+        }
+        debug!("Pubkeys to deny: {pubkeys:?}");
+        repo.deny_pubkeys(&pubkeys, &actor, ChangeSource::Http)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Could not get denied pubkeys".to_string(),
+                )
+            })?;
+    }
+
+    // Remove pubkeys
+    if let Some(pubkeys) = &payload.remove {
+        debug!("Pubkeys to remove: {pubkeys:?}");
+        state
+            .repo
+            .lock()
+            .await
+            .remove_pubkeys(pubkeys, &actor, ChangeSource::Http)
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Could not remove pubkeys".to_string(),
+                )
+            })?;
+    }
+
+    let pubkeys: HashSet<XOnlyPublicKey> = [&payload.allow, &payload.deny, &payload.remove]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .cloned()
+        .collect();
+    if payload.note.is_some() || payload.labels.is_some() {
+        let mut repo = state.repo.lock().await;
+        if let Some(note) = &payload.note {
+            repo.set_note(&pubkeys, note);
+        }
+        if let Some(labels) = &payload.labels {
+            repo.set_labels(&pubkeys, labels);
+        }
+        repo.save_records().await.map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not save user metadata".to_string(),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+async fn get_users(
+    auth: Auth,
+    State(state): State<AppState>,
+    Query(query): Query<UsersQuery>,
+) -> Result<Json<UsersResponse>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    if !query.is_paginated() {
+        let users = state.repo.lock().await.get_users();
+        let encode = |pubkeys: Option<HashSet<XOnlyPublicKey>>| -> Vec<String> {
+            pubkeys
+                .unwrap_or_default()
+                .iter()
+                .map(|p| query.format.encode(p))
+                .collect()
+        };
+
+        return Ok(Json(UsersResponse::List(UserList {
+            allow: encode(users.allow),
+            deny: encode(users.deny),
+        })));
+    }
+
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            decode_cursor(cursor).ok_or((StatusCode::BAD_REQUEST, "Invalid cursor".to_string()))?,
+        ),
+        None => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let descending = query.order.unwrap_or_default().eq(&SortOrder::Desc);
+
+    let (users, next) = state.repo.lock().await.list_users(
+        query.status,
+        query.prefix.as_deref(),
+        cursor,
+        descending,
+        limit,
+    );
+
+    Ok(Json(UsersResponse::Page(UserPage {
+        users: users
+            .iter()
+            .map(|u| UserListEntry {
+                pubkey: query.format.encode(&u.pubkey),
+                status: u.status,
+                added_at: u.added_at,
+            })
+            .collect(),
+        next: next.map(encode_cursor),
+    })))
+}
+
+async fn get_user(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(pubkey): Path<String>,
+    Query(query): Query<UserQuery>,
+) -> Result<Json<UserDetails>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    let pubkey =
+        utils::parse_pubkey(&pubkey).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let (status, record, expires_at) = {
+        let repo = state.repo.lock().await;
+        (
+            repo.get_user_status(pubkey).await,
+            repo.get_record(&pubkey),
+            repo.get_deny_expiry(&pubkey),
+        )
+    };
+
+    let kind = query.kind.unwrap_or(1);
+    let reply = state.authz.preview_decision(pubkey, kind).await;
+
+    Ok(Json(UserDetails {
+        pubkey,
+        npub: pubkey.to_bech32().unwrap_or_default(),
+        status,
+        created_at: record.as_ref().map(|r| r.created_at),
+        updated_at: record.as_ref().map(|r| r.updated_at),
+        updated_by: record.as_ref().map(|r| r.updated_by.clone()),
+        expires_at: expires_at.filter(|_| status.eq(&UserStatus::Denied)),
+        note: record.as_ref().and_then(|r| r.note.clone()),
+        labels: record.map(|r| r.labels).unwrap_or_default(),
+        decision: DecisionPreview {
+            kind,
+            permit: reply.decision.eq(&(Decision::Permit as i32)),
+            message: reply.message,
+        },
+    }))
+}
+
+async fn update_user(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(pubkey): Path<String>,
+    Json(payload): Json<UpdateUser>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+    debug!("User metadata: {payload:?}");
+
+    let pubkey =
+        utils::parse_pubkey(&pubkey).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let mut repo = state.repo.lock().await;
+    if repo.get_record(&pubkey).is_none() {
+        return Err((StatusCode::NOT_FOUND, "Unknown pubkey".to_string()));
+    }
+
+    let pubkeys = HashSet::from([pubkey]);
+    if let Some(note) = &payload.note {
+        repo.set_note(&pubkeys, note);
+    }
+    if let Some(labels) = &payload.labels {
+        repo.set_labels(&pubkeys, labels);
+    }
+    repo.save_records().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not save user metadata".to_string(),
+        )
+    })?;
+
+    Ok(())
+}
+
+async fn get_changes(
+    auth: Auth,
+    State(state): State<AppState>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<Vec<Change>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_changes(query.since)))
+}
+
+async fn get_decisions(
+    auth: Auth,
+    State(state): State<AppState>,
+    Query(query): Query<DecisionsQuery>,
+) -> Result<Json<Vec<DecisionRecord>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    let audit = state.authz.audit.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "Decision log is not enabled".to_string(),
+    ))?;
+    let pubkey = query
+        .pubkey
+        .map(|p| utils::parse_pubkey(&p))
+        .transpose()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    Ok(Json(audit.query(pubkey.as_ref(), query.since)))
+}
+
+async fn revert_change(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Option<Change>>, (StatusCode, String)> {
+    let actor = auth.require(Scope::Admin)?;
+
+    let mut repo = state.repo.lock().await;
+    if repo.get_change(id).is_none() {
+        return Err((StatusCode::NOT_FOUND, "Unknown change".to_string()));
+    }
+
+    let revert = repo.revert_change(id, &actor).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not publish reverted lists".to_string(),
+        )
+    })?;
+
+    Ok(Json(revert))
+}
+
+async fn get_api_keys(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ApiKey>>, (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    Ok(Json(state.repo.lock().await.get_api_keys()))
+}
+
+async fn create_api_key(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(payload): Json<CreateApiKey>,
+) -> Result<Json<ApiKeySecret>, (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    let mut repo = state.repo.lock().await;
+    if repo.api_keys.contains_key(&payload.id) {
+        return Err((StatusCode::CONFLICT, "Api key already exists".to_string()));
+    }
+
+    let (key, secret) = ApiKey::generate(&payload.id, payload.scope, payload.expires_at);
+    repo.insert_api_key(key.clone());
+    repo.save_api_keys().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not save api keys".to_string(),
+        )
+    })?;
+
+    Ok(Json(ApiKeySecret { key, secret }))
+}
+
+async fn revoke_api_key(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    let mut repo = state.repo.lock().await;
+    if repo.api_keys.get(&id).is_some_and(|k| k.from_config) {
+        return Err(config_key_conflict());
+    }
+    if !repo.remove_api_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Unknown api key".to_string()));
+    }
+    repo.save_api_keys().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not save api keys".to_string(),
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Replace the secret of a key, keeping its id, scope and expiry
+async fn rotate_api_key(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApiKeySecret>, (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    let mut repo = state.repo.lock().await;
+    let existing = repo
+        .api_keys
+        .get(&id)
+        .cloned()
+        .ok_or((StatusCode::NOT_FOUND, "Unknown api key".to_string()))?;
+    if existing.from_config {
+        return Err(config_key_conflict());
+    }
+
+    let (key, secret) = ApiKey::generate(&existing.id, existing.scope, existing.expires_at);
+    repo.insert_api_key(key.clone());
+    repo.save_api_keys().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not save api keys".to_string(),
+        )
+    })?;
+
+    Ok(Json(ApiKeySecret { key, secret }))
+}
+
+/// Config keys are read again on restart, so a runtime change to one would be undone
+fn config_key_conflict() -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        "Api key is set in the config and would be restored on restart, change it there instead"
+            .to_string(),
+    )
+}
+
+async fn get_invites(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_invites()))
+}
+
+async fn get_vouches(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_vouches()))
+}
+
+async fn get_content_rules(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ContentRule>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_content_rules()))
+}
+
+async fn update_content_rule(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(rule): Json<ContentRule>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+    debug!("Content rule: {rule:?}");
+
+    ContentFilter::new(vec![rule.clone()])
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    state
+        .repo
+        .lock()
+        .await
+        .insert_content_rule(rule)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn delete_content_rule(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    let removed = state
+        .repo
+        .lock()
+        .await
+        .remove_content_rule(&name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if removed {
+        return Ok(());
+    }
+
+    Err((StatusCode::NOT_FOUND, "Unknown rule".to_string()))
+}
+
+async fn get_kind_rules(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<KindRule>>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_kind_rules()))
+}
+
+async fn update_kind_rule(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(rule): Json<KindRule>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+    debug!("Kind rule: {rule:?}");
+
+    state
+        .repo
+        .lock()
+        .await
+        .insert_kind_rule(rule)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn delete_kind_rule(
+    auth: Auth,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+
+    let removed = state
+        .repo
+        .lock()
+        .await
+        .remove_kind_rule(&name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if removed {
+        return Ok(());
+    }
+
+    Err((StatusCode::NOT_FOUND, "Unknown rule".to_string()))
+}
+
+async fn get_blocklist(
+    auth: Auth,
+    State(state): State<AppState>,
+) -> Result<Json<Blocklist>, (StatusCode, String)> {
+    auth.require(Scope::Read)?;
+
+    Ok(Json(state.repo.lock().await.get_blocklist()))
+}
+
+async fn update_blocklist(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(payload): Json<UpdateBlocklist>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Deny)?;
+    debug!("Block: {payload:?}");
+
+    let (events, hashes) = payload.parse()?;
+    state
+        .repo
+        .lock()
+        .await
+        .block(&events, &hashes)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn delete_from_blocklist(
+    auth: Auth,
+    State(state): State<AppState>,
+    Json(payload): Json<UpdateBlocklist>,
+) -> Result<(), (StatusCode, String)> {
+    auth.require(Scope::Admin)?;
+    debug!("Unblock: {payload:?}");
+
+    let (events, hashes) = payload.parse()?;
+    state
+        .repo
+        .lock()
+        .await
+        .unblock(&events, &hashes)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::Keys;
+
+    use crate::config::Settings;
+    use crate::repo::UserRecord;
+    use crate::utils::unix_time;
+
+    use super::*;
+
+    fn state(settings: Settings) -> AppState {
+        let keys = Keys::generate();
+        let repo = Repo::new(keys.clone(), HashSet::new()).unwrap();
+        let health = repo.health.clone();
+        let metrics = repo.metrics.clone();
+        let repo = Arc::new(Mutex::new(repo));
+
+        AppState {
+            authz: EventAuthz {
+                pubkey: keys.public_key(),
+                repo: repo.clone(),
+                settings,
+                metrics,
+                audit: None,
+            },
+            repo,
+            health,
+        }
+    }
+
+    fn reader() -> Auth {
+        Auth {
+            actor: Actor::ApiKey("reader".to_string()),
+            scope: Scope::Read,
+        }
+    }
+
+    async fn user(state: &AppState, pubkey: &str, kind: Option<u64>) -> UserDetails {
+        get_user(
+            reader(),
+            State(state.clone()),
+            Path(pubkey.to_string()),
+            Query(UserQuery { kind }),
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    #[tokio::test]
+    async fn test_get_user_denied_with_note_and_expiry() {
+        let mut settings = Settings::default();
+        settings.info.deny_reason_in_message = true;
+        let state = state(settings);
+        let pubkey = Keys::generate().public_key();
+        let expires_at = unix_time() + 60;
+        {
+            let mut repo = state.repo.lock().await;
+            repo.denied_pubkeys.insert(pubkey);
+            repo.deny_expiry.insert(pubkey, expires_at);
+            repo.records.insert(
+                pubkey,
+                UserRecord {
+                    created_at: 10,
+                    updated_at: 20,
+                    updated_by: Actor::System("reports".to_string()),
+                    note: Some("spam".to_string()),
+                    labels: BTreeSet::from(["bot".to_string()]),
+                },
+            );
+        }
+
+        let details = user(&state, &pubkey.to_string(), None).await;
+
+        assert_eq!(details.status, UserStatus::Denied);
+        assert_eq!(details.npub, pubkey.to_bech32().unwrap());
+        assert_eq!(details.created_at, Some(10));
+        assert_eq!(details.updated_at, Some(20));
+        assert_eq!(details.expires_at, Some(expires_at));
+        assert_eq!(details.labels, BTreeSet::from(["bot".to_string()]));
+        assert_eq!(details.decision.kind, 1);
+        assert!(!details.decision.permit);
+        assert_eq!(
+            details.decision.message.as_deref(),
+            Some("Not allowed to publish: spam")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_user_expired_denial_is_unknown() {
+        let mut settings = Settings::default();
+        settings.pow.difficulty = Some(20);
+        let state = state(settings);
+        let pubkey = Keys::generate().public_key();
+        {
+            let mut repo = state.repo.lock().await;
+            repo.denied_pubkeys.insert(pubkey);
+            repo.deny_expiry.insert(pubkey, unix_time() - 1);
+        }
+
+        let details = user(&state, &pubkey.to_string(), Some(7)).await;
+
+        assert_eq!(details.status, UserStatus::Unknown);
+        assert_eq!(details.expires_at, None);
+        assert_eq!(details.created_at, None);
+        assert_eq!(details.decision.kind, 7);
+        assert_eq!(
+            details.decision.message.as_deref(),
+            Some("pow: difficulty 20 required")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_user_invalid_pubkey() {
+        let state = state(Settings::default());
+
+        let err = get_user(
+            reader(),
+            State(state),
+            Path("npub1invalid".to_string()),
+            Query(UserQuery { kind: None }),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_update_blocklist_parse() {
+        let id = "AB".repeat(32);
+        let update: UpdateBlocklist = serde_json::from_value(serde_json::json!({
+            "events": [id],
+            "contents": ["Buy  NOW"],
+        }))
+        .unwrap();
+        let (events, hashes) = update.parse().unwrap();
+        assert_eq!(events, HashSet::from(["ab".repeat(32)]));
+        assert_eq!(hashes, HashSet::from([utils::content_hash("buy now")]));
+
+        for invalid in [
+            serde_json::json!({ "events": ["ab".repeat(31)] }),
+            serde_json::json!({ "hashes": ["zz".repeat(32)] }),
+        ] {
+            let update: UpdateBlocklist = serde_json::from_value(invalid).unwrap();
+            assert_eq!(update.parse().unwrap_err().0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_blocklist_needs_deny_scope() {
+        let state = state(Settings::default());
+        let update = |scope| {
+            update_blocklist(
+                Auth {
+                    actor: Actor::ApiKey("test".to_string()),
+                    scope,
+                },
+                State(state.clone()),
+                Json(UpdateBlocklist {
+                    events: HashSet::from(["ab".repeat(32)]),
+                    hashes: HashSet::new(),
+                    contents: Vec::new(),
+                }),
+            )
+        };
+
+        assert_eq!(
+            update(Scope::Read).await.unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+        // Publishing fails without relays, after the scope check and the update
+        let err = update(Scope::Deny).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(state
+            .repo
+            .lock()
+            .await
+            .get_blocklist()
+            .events
+            .contains(&"ab".repeat(32)));
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let pubkey = Keys::generate().public_key();
+        let cursor = encode_cursor((1_700_000_000, pubkey));
+
+        assert_eq!(cursor, format!("1700000000:{pubkey}"));
+        assert_eq!(decode_cursor(&cursor), Some((1_700_000_000, pubkey)));
+    }
+
+    #[test]
+    fn test_decode_invalid_cursor() {
+        let pubkey = Keys::generate().public_key();
+
+        assert_eq!(decode_cursor(""), None);
+        assert_eq!(decode_cursor("1700000000"), None);
+        assert_eq!(decode_cursor(&format!("soon:{pubkey}")), None);
+        assert_eq!(decode_cursor("1700000000:not a pubkey"), None);
+        assert_eq!(decode_cursor(&format!("-1:{pubkey}")), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! NIP-86 relay management JSON-RPC api
+
+use std::collections::HashSet;
+
+use axum::body::Bytes;
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use nostr_sdk::key::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{AppState, Auth};
+use crate::auth::Scope;
+use crate::repo::{Actor, ChangeSource, Repo};
+use crate::utils;
+
+const SUPPORTED_METHODS: [&str; 10] = [
+    "supportedmethods",
+    "banpubkey",
+    "unbanpubkey",
+    "listbannedpubkeys",
+    "allowpubkey",
+    "unallowpubkey",
+    "listallowedpubkeys",
+    "banevent",
+    "allowevent",
+    "listbannedevents",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    result: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Scope an api key needs for `method`
+fn required_scope(method: &str) -> Scope {
+    match method {
+        "supportedmethods" | "listbannedpubkeys" | "listallowedpubkeys" | "listbannedevents" => {
+            Scope::Read
+        }
+        "banpubkey" | "banevent" => Scope::Deny,
+        _ => Scope::Admin,
+    }
+}
+
+/// Handle a NIP-86 request, the body is parsed here as its content type is `application/nostr+json+rpc`
+pub async fn handle(
+    auth: Auth,
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<RpcResponse>, (StatusCode, String)> {
+    let request: RpcRequest =
+        serde_json::from_slice(&body).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let actor = auth.require(required_scope(&request.method))?;
+
+    let mut repo = state.repo.lock().await;
+    let response = match call(&mut repo, &actor, &request).await {
+        Ok(result) => RpcResponse {
+            result,
+            error: None,
+        },
+        Err(err) => RpcResponse {
+            result: Value::Null,
+            error: Some(err.to_string()),
+        },
+    };
+
+    Ok(Json(response))
+}
+
+fn pubkey_param(params: &[Value]) -> anyhow::Result<XOnlyPublicKey> {
+    let pubkey = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or(anyhow::anyhow!("Missing pubkey"))?;
+
+    utils::parse_pubkey(pubkey)
+}
+
+fn event_param(params: &[Value]) -> anyhow::Result<String> {
+    let id = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or(anyhow::anyhow!("Missing event id"))?;
+
+    utils::parse_hex32(id).map_err(|_| anyhow::anyhow!("Invalid event id"))
+}
+
+fn reason_param(params: &[Value]) -> Option<&str> {
+    params
+        .get(1)
+        .and_then(Value::as_str)
+        .filter(|r| !r.is_empty())
+}
+
+/// Pubkeys with the notes recorded as their reasons
+fn with_reasons(repo: &Repo, pubkeys: &HashSet<XOnlyPublicKey>) -> Value {
+    pubkeys
+        .iter()
+        .map(|p| {
+            json!({
+                "pubkey": p.to_string(),
+                "reason": repo.get_record(p).and_then(|r| r.note),
+            })
+        })
+        .collect()
+}
+
+async fn call(repo: &mut Repo, actor: &Actor, request: &RpcRequest) -> anyhow::Result<Value> {
+    let params = &request.params;
+
+    match request.method.as_str() {
+        "supportedmethods" => Ok(json!(SUPPORTED_METHODS)),
+        "banpubkey" => {
+            let pubkeys = HashSet::from([pubkey_param(params)?]);
+            repo.deny_pubkeys(&pubkeys, actor, ChangeSource::Nip86)
+                .await?;
+            if let Some(reason) = reason_param(params) {
+                repo.set_note(&pubkeys, reason);
+                repo.save_records().await?;
+            }
+            Ok(json!(true))
+        }
+        "allowpubkey" => {
+            let pubkeys = HashSet::from([pubkey_param(params)?]);
+            repo.admit_pubkeys(&pubkeys, actor, ChangeSource::Nip86)
+                .await?;
+            if let Some(reason) = reason_param(params) {
+                repo.set_note(&pubkeys, reason);
+                repo.save_records().await?;
+            }
+            Ok(json!(true))
+        }
+        "unbanpubkey" | "unallowpubkey" => {
+            let pubkey = pubkey_param(params)?;
+            let listed = match request.method.as_str() {
+                "unbanpubkey" => repo.denied_pubkeys.contains(&pubkey),
+                _ => repo.allowed_pubkeys.contains(&pubkey),
+            };
+            if listed {
+                repo.remove_pubkeys(&HashSet::from([pubkey]), actor, ChangeSource::Nip86)
+                    .await?;
+            }
+            Ok(json!(true))
+        }
+        "listbannedpubkeys" => Ok(with_reasons(repo, &repo.denied_pubkeys)),
+        "listallowedpubkeys" => Ok(with_reasons(repo, &repo.allowed_pubkeys)),
+        "banevent" => {
+            repo.block(&HashSet::from([event_param(params)?]), &HashSet::new())
+                .await?;
+            Ok(json!(true))
+        }
+        "allowevent" => {
+            repo.unblock(&HashSet::from([event_param(params)?]), &HashSet::new())
+                .await?;
+            Ok(json!(true))
+        }
+        "listbannedevents" => Ok(repo
+            .get_blocklist()
+            .events
+            .iter()
+            .map(|id| json!({ "id": id }))
+            .collect()),
+        method => anyhow::bail!("Unsupported method {method}"),
+    }
+}
@@ -0,0 +1,312 @@
+//! Structured log of `EventAdmit` decisions, kept in memory and optionally appended to a rotating file
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use nostr_sdk::key::XOnlyPublicKey;
+use serde::Serialize;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::config::Audit;
+use crate::nauthz_grpc::{Decision, EventReply, EventRequest};
+use crate::utils::unix_time;
+use crate::DecisionReason;
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+const DEFAULT_BUFFER_SIZE: usize = 10_000;
+
+/// Records waiting to be written before new ones are dropped
+const WRITE_QUEUE: usize = 4096;
+
+/// Decision on an event with the request metadata it was made with
+#[derive(Debug, Clone, Serialize)]
+pub struct DecisionRecord {
+    /// Unix time of the decision
+    pub time: u64,
+    pub event_id: String,
+    pub kind: u64,
+    pub author: String,
+    /// NIP-42 authenticated pubkey of the session
+    pub auth_pubkey: Option<String>,
+    pub ip: Option<String>,
+    pub origin: Option<String>,
+    /// `permit` or `deny`
+    pub decision: String,
+    /// Rule the event was decided by, such as `allowed` or `content_rule`
+    pub reason: String,
+    /// Name of the content or kind rule
+    pub rule: Option<String>,
+    pub message: Option<String>,
+}
+
+impl DecisionRecord {
+    /// Record of a decision, `None` if the request has no event
+    pub fn new(req: &EventRequest, reply: &EventReply, reason: &DecisionReason) -> Option<Self> {
+        let event = req.event.as_ref()?;
+        let decision = match reply.decision() {
+            Decision::Permit => "permit",
+            _ => "deny",
+        };
+
+        Some(Self {
+            time: unix_time(),
+            event_id: ::hex::encode(&event.id),
+            kind: event.kind,
+            author: ::hex::encode(&event.pubkey),
+            auth_pubkey: req.auth_pubkey.as_ref().map(::hex::encode),
+            ip: req.ip_addr.clone(),
+            origin: req.origin.clone(),
+            decision: decision.to_string(),
+            reason: reason.label().to_string(),
+            rule: reason.rule().map(str::to_string),
+            message: reply.message.clone(),
+        })
+    }
+}
+
+pub struct AuditLog {
+    sample_rate: f64,
+    buffer_size: usize,
+    records: Mutex<VecDeque<DecisionRecord>>,
+    sender: Option<mpsc::Sender<String>>,
+}
+
+impl AuditLog {
+    /// Start writing to the file of `settings`, fails if it cannot be opened
+    pub async fn new(settings: &Audit) -> Result<Self> {
+        let sender = match &settings.file {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let file = open(&path).await?;
+                let (sender, receiver) = mpsc::channel(WRITE_QUEUE);
+                tokio::spawn(write_lines(
+                    path,
+                    file,
+                    settings.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+                    settings.max_files.unwrap_or(DEFAULT_MAX_FILES),
+                    receiver,
+                ));
+                Some(sender)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            sample_rate: settings.sample_rate.unwrap_or(1.0),
+            buffer_size: settings.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            records: Mutex::new(VecDeque::new()),
+            sender,
+        })
+    }
+
+    /// Whether the event is sampled, decided by its id so every instance samples the same events
+    pub fn sampled(&self, event_id: &[u8]) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+
+        let mut prefix = [0u8; 8];
+        let len = event_id.len().min(8);
+        prefix[..len].copy_from_slice(&event_id[..len]);
+
+        (u64::from_be_bytes(prefix) as f64) < self.sample_rate * u64::MAX as f64
+    }
+
+    pub fn record(&self, record: DecisionRecord) {
+        if let Some(sender) = &self.sender {
+            match serde_json::to_string(&record) {
+                Ok(line) => {
+                    if sender.try_send(line).is_err() {
+                        warn!("Decision log is falling behind, dropped a record");
+                    }
+                }
+                Err(err) => warn!("Could not serialize decision: {err}"),
+            }
+        }
+
+        if self.buffer_size == 0 {
+            return;
+        }
+        if let Ok(mut records) = self.records.lock() {
+            if records.len() >= self.buffer_size {
+                records.pop_front();
+            }
+            records.push_back(record);
+        }
+    }
+
+    /// Buffered decisions made at or after `since` on events authored or sent by `pubkey`, oldest first
+    pub fn query(&self, pubkey: Option<&XOnlyPublicKey>, since: u64) -> Vec<DecisionRecord> {
+        let pubkey = pubkey.map(|p| p.to_string());
+
+        match self.records.lock() {
+            Ok(records) => records
+                .iter()
+                .filter(|r| r.time >= since)
+                .filter(|r| {
+                    pubkey.as_ref().is_none_or(|p| {
+                        r.author.eq(p) || r.auth_pubkey.as_ref().is_some_and(|a| a.eq(p))
+                    })
+                })
+                .cloned()
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+async fn open(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+/// Append lines to `path`, moving it to `<path>.1` and shifting older files once it exceeds `max_size`
+async fn write_lines(
+    path: PathBuf,
+    mut file: File,
+    max_size: u64,
+    max_files: usize,
+    mut receiver: mpsc::Receiver<String>,
+) {
+    let mut size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+
+    while let Some(mut line) = receiver.recv().await {
+        line.push('\n');
+
+        if size > 0 && size + line.len() as u64 > max_size {
+            match rotate(&path, max_files).await {
+                Ok(rotated) => {
+                    file = rotated;
+                    size = 0;
+                }
+                Err(err) => warn!("Could not rotate decision log: {err}"),
+            }
+        }
+
+        match file.write_all(line.as_bytes()).await {
+            Ok(()) => size += line.len() as u64,
+            Err(err) => warn!("Could not write decision log: {err}"),
+        }
+    }
+}
+
+async fn rotate(path: &Path, max_files: usize) -> Result<File> {
+    let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+
+    if max_files == 0 {
+        fs::remove_file(path).await?;
+    } else {
+        for n in (1..max_files).rev() {
+            if fs::metadata(rotated(n)).await.is_ok() {
+                fs::rename(rotated(n), rotated(n + 1)).await?;
+            }
+        }
+        fs::rename(path, rotated(1)).await?;
+    }
+
+    open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::Keys;
+
+    use super::*;
+
+    async fn audit_log(sample_rate: Option<f64>, buffer_size: Option<usize>) -> AuditLog {
+        AuditLog::new(&Audit {
+            enabled: true,
+            sample_rate,
+            buffer_size,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn decision(
+        time: u64,
+        author: &XOnlyPublicKey,
+        auth_pubkey: Option<&XOnlyPublicKey>,
+    ) -> DecisionRecord {
+        DecisionRecord {
+            time,
+            event_id: "00".repeat(32),
+            kind: 1,
+            author: author.to_string(),
+            auth_pubkey: auth_pubkey.map(|p| p.to_string()),
+            ip: None,
+            origin: None,
+            decision: "permit".to_string(),
+            reason: "allowed".to_string(),
+            rule: None,
+            message: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sampled_by_event_id() {
+        let id = |first: u8| {
+            let mut id = [0xffu8; 32];
+            id[0] = first;
+            id
+        };
+
+        let all = audit_log(None, None).await;
+        assert!(all.sampled(&id(0xff)));
+
+        let none = audit_log(Some(0.0), None).await;
+        assert!(!none.sampled(&id(0x00)));
+        assert!(!none.sampled(&[0; 32]));
+
+        let half = audit_log(Some(0.5), None).await;
+        assert!(half.sampled(&id(0x00)));
+        assert!(half.sampled(&id(0x7e)));
+        assert!(!half.sampled(&id(0x80)));
+        assert!(!half.sampled(&id(0xff)));
+        // The same id is always sampled the same way
+        assert_eq!(half.sampled(&id(0x42)), half.sampled(&id(0x42)));
+
+        // Ids shorter than the prefix are padded with zeros
+        assert!(half.sampled(&[0x7f]));
+        assert!(!half.sampled(&[0x80]));
+    }
+
+    #[tokio::test]
+    async fn test_record_drops_oldest_and_query_filters() {
+        let log = audit_log(None, Some(3)).await;
+        let author = Keys::generate().public_key();
+        let sender = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+
+        log.record(decision(10, &other, None));
+        log.record(decision(20, &author, None));
+        log.record(decision(30, &other, Some(&sender)));
+        log.record(decision(40, &other, None));
+
+        let times =
+            |records: Vec<DecisionRecord>| -> Vec<u64> { records.iter().map(|r| r.time).collect() };
+        assert_eq!(times(log.query(None, 0)), vec![20, 30, 40]);
+        assert_eq!(times(log.query(None, 30)), vec![30, 40]);
+        assert_eq!(times(log.query(Some(&author), 0)), vec![20]);
+        assert_eq!(times(log.query(Some(&sender), 0)), vec![30]);
+        assert_eq!(times(log.query(Some(&other), 35)), vec![40]);
+    }
+
+    #[tokio::test]
+    async fn test_record_without_buffer() {
+        let log = audit_log(None, Some(0)).await;
+        log.record(decision(10, &Keys::generate().public_key(), None));
+
+        assert!(log.query(None, 0).is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+//! Api keys and NIP-98 authentication of the http api
+
+use ::url::{Position, Url};
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use nostr_sdk::hashes::{sha256, Hash};
+use nostr_sdk::key::XOnlyPublicKey;
+use nostr_sdk::secp256k1::rand;
+use nostr_sdk::{Event, Kind};
+use serde::{Deserialize, Serialize};
+use tonic::service::Interceptor;
+use tonic::Status;
+
+use crate::utils::unix_time;
+
+/// What an api key is allowed to do, each scope includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read users, rules and logs
+    Read,
+    /// Also deny pubkeys
+    Deny,
+    /// Everything, including managing api keys
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Unique name of the key, recorded as the actor of the changes made with it
+    pub id: String,
+    /// Hex SHA256 of the secret
+    #[serde(skip_serializing)]
+    pub hash: String,
+    pub scope: Scope,
+    /// Unix time after which the key is rejected
+    pub expires_at: Option<u64>,
+    /// Set in the config, which is read again on restart so the key cannot be revoked or rotated at runtime
+    #[serde(default, skip_deserializing)]
+    pub from_config: bool,
+}
+
+impl ApiKey {
+    /// New key with a random secret, returns the key and its secret
+    pub fn generate(id: &str, scope: Scope, expires_at: Option<u64>) -> (Self, String) {
+        let secret = ::hex::encode(rand::random::<[u8; 32]>());
+
+        (
+            Self {
+                id: id.to_string(),
+                hash: hash_secret(&secret),
+                scope,
+                expires_at,
+                from_config: false,
+            },
+            secret,
+        )
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= unix_time())
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        self.hash.eq_ignore_ascii_case(&hash_secret(secret))
+    }
+}
+
+/// Hex SHA256 of an api key secret
+pub fn hash_secret(secret: &str) -> String {
+    sha256::Hash::hash(secret.as_bytes()).to_string()
+}
+
+/// Request checked against a NIP-98 auth event
+pub struct HttpRequest<'a> {
+    /// Value of the `Host` header
+    pub host: &'a str,
+    /// Path and query of the request
+    pub path_and_query: &'a str,
+    pub method: &'a str,
+    pub body: &'a [u8],
+}
+
+/// Verify a NIP-98 `Authorization: Nostr <base64 event>` header, returns the signer
+///
+/// The event has to be a valid kind 27235 event created within `max_age` seconds of now, with a `u` tag
+/// for the requested url, a `method` tag for the request method and, if the request has a body,
+/// a `payload` tag with its hex SHA256. The scheme of the url is not checked so the api can be run behind a TLS proxy.
+pub fn verify_nip98(header: &str, request: &HttpRequest, max_age: u64) -> Result<XOnlyPublicKey> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or(anyhow!("Not a Nostr authorization"))?;
+    let json = base64::engine::general_purpose::STANDARD.decode(encoded.trim())?;
+    let event = Event::from_json(String::from_utf8(json)?)?;
+
+    if event.kind.ne(&Kind::HttpAuth) {
+        bail!("Auth event must be kind 27235");
+    }
+
+    if event.created_at.as_u64().abs_diff(unix_time()) > max_age {
+        bail!("Auth event is not recent");
+    }
+
+    let tag = |name: &str| -> Option<String> {
+        event
+            .tags
+            .iter()
+            .map(|t| t.as_vec())
+            .find(|t| t.first().is_some_and(|n| n.eq(name)))
+            .and_then(|t| t.get(1).cloned())
+    };
+
+    let url = Url::parse(&tag("u").ok_or(anyhow!("Auth event has no u tag"))?)?;
+    let expected = format!("{}{}", request.host, request.path_and_query);
+    if !url[Position::BeforeHost..Position::AfterQuery].eq(&expected) {
+        bail!("Auth event url does not match the request");
+    }
+
+    if !tag("method").is_some_and(|m| m.eq_ignore_ascii_case(request.method)) {
+        bail!("Auth event method does not match the request");
+    }
+
+    match tag("payload") {
+        Some(payload)
+            if !payload.eq_ignore_ascii_case(&sha256::Hash::hash(request.body).to_string()) =>
+        {
+            bail!("Auth event payload does not match the request body")
+        }
+        None if !request.body.is_empty() => bail!("Auth event has no payload tag"),
+        _ => (),
+    }
+
+    Ok(event.pubkey)
+}
+
+/// Interceptor checking the shared secret of the grpc `Authorization` service
+#[derive(Clone)]
+pub struct GrpcToken {
+    /// Hex SHA256 of the token, sent tokens are compared by hash so the comparison does not leak the token
+    hash: Option<String>,
+}
+
+impl GrpcToken {
+    /// Requests are not checked if no token is set
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            hash: token.as_deref().map(hash_secret),
+        }
+    }
+}
+
+impl Interceptor for GrpcToken {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        let Some(hash) = &self.hash else {
+            return Ok(request);
+        };
+
+        match request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            Some(sent) if hash_secret(sent).eq(hash) => Ok(request),
+            Some(_) => Err(Status::unauthenticated("Invalid token")),
+            None => Err(Status::unauthenticated("No token")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::{EventBuilder, EventId, Keys, Tag, Timestamp};
+
+    use super::*;
+
+    const BODY: &[u8] = br#"{"allow":[]}"#;
+
+    fn header(keys: &Keys, kind: Kind, created_at: u64, tags: &[&[&str]]) -> String {
+        let tags: Vec<Tag> = tags
+            .iter()
+            .map(|t| Tag::parse(t.to_vec()).unwrap())
+            .collect();
+        let mut unsigned = EventBuilder::new(kind, "", &tags).to_unsigned_event(keys.public_key());
+        unsigned.created_at = Timestamp::from(created_at);
+        unsigned.id = EventId::new(
+            &unsigned.pubkey,
+            unsigned.created_at,
+            &unsigned.kind,
+            &unsigned.tags,
+            &unsigned.content,
+        );
+        let event = unsigned.sign(keys).unwrap();
+
+        format!(
+            "Nostr {}",
+            base64::engine::general_purpose::STANDARD.encode(event.as_json())
+        )
+    }
+
+    fn request<'a>(method: &'a str, body: &'a [u8]) -> HttpRequest<'a> {
+        HttpRequest {
+            host: "relay.example:3000",
+            path_and_query: "/update?dry_run=true",
+            method,
+            body,
+        }
+    }
+
+    fn body_hash() -> String {
+        sha256::Hash::hash(BODY).to_string()
+    }
+
+    #[test]
+    fn test_verify_nip98_returns_signer() {
+        let keys = Keys::generate();
+        let hash = body_hash();
+        let header = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[
+                &["u", "https://relay.example:3000/update?dry_run=true"],
+                &["method", "POST"],
+                &["payload", &hash],
+            ],
+        );
+
+        let signer = verify_nip98(&header, &request("POST", BODY), 60).unwrap();
+        assert_eq!(signer, keys.public_key());
+    }
+
+    #[test]
+    fn test_verify_nip98_ignores_scheme() {
+        let keys = Keys::generate();
+        let header = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[
+                &["u", "http://relay.example:3000/update?dry_run=true"],
+                &["method", "GET"],
+            ],
+        );
+
+        assert!(verify_nip98(&header, &request("GET", b""), 60).is_ok());
+    }
+
+    #[test]
+    fn test_verify_nip98_url_mismatch() {
+        let keys = Keys::generate();
+        for url in [
+            "https://other.example:3000/update?dry_run=true",
+            "https://relay.example/update?dry_run=true",
+            "https://relay.example:3000/update",
+            "https://relay.example:3000/users?dry_run=true",
+        ] {
+            let header = header(
+                &keys,
+                Kind::HttpAuth,
+                unix_time(),
+                &[&["u", url], &["method", "GET"]],
+            );
+            assert!(
+                verify_nip98(&header, &request("GET", b""), 60).is_err(),
+                "{url}"
+            );
+        }
+
+        let header = header(&keys, Kind::HttpAuth, unix_time(), &[&["method", "GET"]]);
+        assert!(verify_nip98(&header, &request("GET", b""), 60).is_err());
+    }
+
+    #[test]
+    fn test_verify_nip98_method_mismatch() {
+        let keys = Keys::generate();
+        let url = "https://relay.example:3000/update?dry_run=true";
+
+        let header_get = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[&["u", url], &["method", "GET"]],
+        );
+        assert!(verify_nip98(&header_get, &request("DELETE", b""), 60).is_err());
+
+        let header_none = header(&keys, Kind::HttpAuth, unix_time(), &[&["u", url]]);
+        assert!(verify_nip98(&header_none, &request("GET", b""), 60).is_err());
+    }
+
+    #[test]
+    fn test_verify_nip98_payload() {
+        let keys = Keys::generate();
+        let url = "https://relay.example:3000/update?dry_run=true";
+
+        let without_payload = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[&["u", url], &["method", "POST"]],
+        );
+        assert!(verify_nip98(&without_payload, &request("POST", BODY), 60).is_err());
+        assert!(verify_nip98(&without_payload, &request("POST", b""), 60).is_ok());
+
+        let other_hash = sha256::Hash::hash(b"{}").to_string();
+        let wrong_payload = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[&["u", url], &["method", "POST"], &["payload", &other_hash]],
+        );
+        assert!(verify_nip98(&wrong_payload, &request("POST", BODY), 60).is_err());
+
+        let hash = body_hash().to_uppercase();
+        let uppercase_payload = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[&["u", url], &["method", "POST"], &["payload", &hash]],
+        );
+        assert!(verify_nip98(&uppercase_payload, &request("POST", BODY), 60).is_ok());
+    }
+
+    #[test]
+    fn test_verify_nip98_freshness() {
+        let keys = Keys::generate();
+        let tags: &[&[&str]] = &[
+            &["u", "https://relay.example:3000/update?dry_run=true"],
+            &["method", "GET"],
+        ];
+
+        for (created_at, fresh) in [
+            (unix_time() - 50, true),
+            (unix_time() + 50, true),
+            (unix_time() - 120, false),
+            (unix_time() + 120, false),
+        ] {
+            let header = header(&keys, Kind::HttpAuth, created_at, tags);
+            assert_eq!(
+                verify_nip98(&header, &request("GET", b""), 60).is_ok(),
+                fresh
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_nip98_rejects_wrong_kind_and_header() {
+        let keys = Keys::generate();
+        let tags: &[&[&str]] = &[
+            &["u", "https://relay.example:3000/update?dry_run=true"],
+            &["method", "GET"],
+        ];
+
+        let text_note = header(&keys, Kind::TextNote, unix_time(), tags);
+        assert!(verify_nip98(&text_note, &request("GET", b""), 60).is_err());
+
+        let valid = header(&keys, Kind::HttpAuth, unix_time(), tags);
+        let bearer = valid.replacen("Nostr ", "Bearer ", 1);
+        assert!(verify_nip98(&bearer, &request("GET", b""), 60).is_err());
+        assert!(verify_nip98("Nostr not-base64!", &request("GET", b""), 60).is_err());
+    }
+
+    #[test]
+    fn test_verify_nip98_rejects_bad_signature() {
+        let keys = Keys::generate();
+        let header = header(
+            &keys,
+            Kind::HttpAuth,
+            unix_time(),
+            &[
+                &["u", "https://relay.example:3000/update?dry_run=true"],
+                &["method", "GET"],
+            ],
+        );
+        let json = base64::engine::general_purpose::STANDARD
+            .decode(header.trim_start_matches("Nostr "))
+            .unwrap();
+        let tampered = String::from_utf8(json)
+            .unwrap()
+            .replace("\"GET\"", "\"PUT\"");
+        let tampered = format!(
+            "Nostr {}",
+            base64::engine::general_purpose::STANDARD.encode(tampered)
+        );
+
+        assert!(verify_nip98(&tampered, &request("PUT", b""), 60).is_err());
+    }
+
+    fn grpc_request(authorization: Option<&str>) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn test_grpc_token() {
+        let mut interceptor = GrpcToken::new(Some("secret".to_string()));
+
+        assert!(interceptor
+            .call(grpc_request(Some("Bearer secret")))
+            .is_ok());
+        assert!(interceptor
+            .call(grpc_request(Some("Bearer secre")))
+            .is_err());
+        assert!(interceptor.call(grpc_request(Some("secret"))).is_err());
+        assert!(interceptor.call(grpc_request(None)).is_err());
+
+        let mut open = GrpcToken::new(None);
+        assert!(open.call(grpc_request(None)).is_ok());
+    }
+}
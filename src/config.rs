@@ -28,13 +28,19 @@ THE SOFTWARE.
 //!
 //!
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use config::{Config, ConfigError, File};
 use log::warn;
+use nostr_sdk::key::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::auth::ApiKey;
+use crate::content::ContentRule;
+use crate::kinds::KindRule;
+use crate::utils;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Info {
     pub private_key: String,
@@ -44,13 +50,177 @@ pub struct Info {
     pub api_listen_port: Option<u16>,
     pub grpc_listen_host: Option<String>,
     pub grpc_listen_port: Option<u16>,
+    /// Paths of the PEM certificate and key to serve the http api over TLS
+    pub api_tls_cert: Option<String>,
+    pub api_tls_key: Option<String>,
+    /// Paths of the PEM certificate and key to serve grpc over TLS
+    pub grpc_tls_cert: Option<String>,
+    pub grpc_tls_key: Option<String>,
     pub db_path: Option<String>,
     pub implicit_allow: bool,
+    /// Admit NIP-09 deletions from denied and unknown pubkeys of their own events
+    pub allow_self_deletion: bool,
+    /// Include the note of a denied pubkey in the message returned to it
+    pub deny_reason_in_message: bool,
+    /// Changes kept in the change log, oldest first to go, defaults to 10000
+    pub change_log_size: Option<usize>,
+    /// Accept NIP-98 auth events signed by the relay key or one of `nip98_admins` on the http api
+    pub nip98_auth: bool,
+    /// Seconds a NIP-98 auth event is accepted for, defaults to 60
+    pub nip98_max_age: Option<u64>,
+    /// Pubkeys whose NIP-98 signed requests have the `admin` scope on the http api
+    #[serde(default, deserialize_with = "utils::deserialize_pubkeys")]
+    pub nip98_admins: HashSet<XOnlyPublicKey>,
+    /// Pubkeys trusted to publish anything: always admitted, never denied by reports, exempt from the
+    /// content, blocklist and denied reference rules, the only pubkeys admitted by kind rules with the
+    /// `admins` policy and sent a direct message on automatic denials
+    #[serde(default, deserialize_with = "utils::deserialize_pubkeys")]
+    pub admins: HashSet<XOnlyPublicKey>,
+}
+
+/// Member-to-member invitations
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Invites {
+    /// Kind of the invite events members publish, invites are disabled if not set
+    pub kind: Option<u64>,
+    /// Number of pubkeys each member is allowed to invite
+    pub quota: usize,
+}
+
+/// Admission of pubkeys vouched for by members
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Vouches {
+    /// Kind of the vouch events members publish, vouching is disabled if not set
+    pub kind: Option<u64>,
+    /// Number of distinct members that have to vouch for a pubkey before it is allowed
+    pub threshold: usize,
+}
+
+/// NIP-56 report handling
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Reports {
+    /// Tally reports and deny pubkeys once a threshold is crossed
+    pub enabled: bool,
+    /// Pubkeys whose reports are counted even if they are not members
+    #[serde(default, deserialize_with = "utils::deserialize_pubkeys")]
+    pub trusted_reporters: HashSet<XOnlyPublicKey>,
+    /// Number of distinct reporters of a report type needed to deny a pubkey, 0 disables automatic denial
+    pub threshold: usize,
+    /// Thresholds overriding `threshold` for specific report types
+    #[serde(default)]
+    pub thresholds: HashMap<String, usize>,
+    /// Seconds an automatic denial lasts, denials are permanent if not set
+    pub ban_duration: Option<u64>,
+}
+
+/// NIP-13 proof of work required for events of a kind
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KindDifficulty {
+    pub kind: u64,
+    pub difficulty: u8,
+}
+
+/// NIP-13 proof of work admission of unknown pubkeys
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Pow {
+    /// Difficulty that admits events of any kind from unknown pubkeys, disabled if not set
+    pub difficulty: Option<u8>,
+    /// Difficulties overriding `difficulty` for specific kinds
+    #[serde(default)]
+    pub kinds: Vec<KindDifficulty>,
+    /// Only count the difficulty committed to by the `nonce` tag
+    pub require_nonce: bool,
+}
+
+impl Pow {
+    /// Difficulty required to admit an event of `kind`, if any
+    pub fn required_difficulty(&self, kind: u64) -> Option<u8> {
+        self.kinds
+            .iter()
+            .find(|k| k.kind.eq(&kind))
+            .map(|k| k.difficulty)
+            .or(self.difficulty)
+    }
+}
+
+/// Admission of events from unknown pubkeys addressed to members
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Inbound {
+    /// Admit events from unknown pubkeys that `p` tag a member
+    pub enabled: bool,
+    /// Kinds that are admitted when addressed to a member
+    #[serde(default)]
+    pub kinds: HashSet<u64>,
+    /// Maximum number of inbound events admitted per sender each minute, unlimited if not set
+    pub rate_limit: Option<u32>,
+}
+
+/// Denial of events referencing denied pubkeys
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DeniedReferences {
+    /// Deny events that reference denied pubkeys
+    pub enabled: bool,
+    /// Kinds the rule applies to, all kinds if empty
+    #[serde(default)]
+    pub kinds: HashSet<u64>,
+    /// Look up the authors of events referenced by `e` tags without an author hint on the relays
+    pub lookup_event_authors: bool,
+    /// Message returned to the client, defaults to `blocked: references a denied user`
+    pub message: Option<String>,
+}
+
+/// Authentication of the clients of the grpc server
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GrpcAuth {
+    /// Shared secret `EventAdmit` calls have to send as `authorization: Bearer <token>` metadata
+    pub token: Option<String>,
+    /// Path of the PEM CA client certificates have to be signed by, enables mutual TLS with `grpc_tls_cert`
+    pub tls_client_ca: Option<String>,
+}
+
+/// gRPC service to manage users, authenticated with the api keys
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GrpcAdmin {
+    pub enabled: bool,
+}
+
+/// Structured log of the decisions made by `EventAdmit`
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Audit {
+    pub enabled: bool,
+    /// Path of a file decisions are appended to as JSON lines
+    pub file: Option<String>,
+    /// Bytes after which the file is rotated, defaults to 10MB
+    pub max_file_size: Option<u64>,
+    /// Rotated files kept as `<file>.1`, `<file>.2`..., defaults to 5
+    pub max_files: Option<usize>,
+    /// Recent decisions kept in memory for `GET /decisions`, defaults to 10000
+    pub buffer_size: Option<usize>,
+    /// Fraction of events recorded, chosen by event id, defaults to 1
+    pub sample_rate: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Settings {
     pub info: Info,
+    pub invites: Invites,
+    pub vouches: Vouches,
+    pub reports: Reports,
+    pub pow: Pow,
+    pub inbound: Inbound,
+    pub denied_references: DeniedReferences,
+    /// Content rules applied to events of everyone except admins
+    #[serde(default)]
+    pub content_rules: Vec<ContentRule>,
+    /// Rules deciding who may publish events of a kind, the first matching rule applies
+    #[serde(default)]
+    pub kind_rules: Vec<KindRule>,
+    pub grpc_auth: GrpcAuth,
+    pub grpc_admin: GrpcAdmin,
+    pub audit: Audit,
+    /// Hashed http api keys in addition to `api_key`
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
 }
 
 impl Settings {
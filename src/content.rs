@@ -0,0 +1,317 @@
+//! Content and tag rules applied to events of everyone except admins
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::nauthz_grpc::Event;
+
+/// What an event is matched against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentMatch {
+    /// Content contains the keyword, ignoring case
+    Keyword { keyword: String },
+    /// Content matches the regex
+    Regex { pattern: String },
+    /// Event has a `t` tag with the hashtag, ignoring case
+    Hashtag { hashtag: String },
+    /// Content is longer than `length` characters
+    MaxLength { length: usize },
+    /// Event has more than `count` tags
+    MaxTags { count: usize },
+    /// Content links to the domain or one of its subdomains
+    Domain { domain: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentRule {
+    /// Unique name of the rule
+    pub name: String,
+    #[serde(flatten)]
+    pub matches: ContentMatch,
+    /// Message returned to the client when an event matches the rule
+    pub message: String,
+}
+
+/// Content rules with their regexes compiled
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilter {
+    rules: Vec<(ContentRule, Option<Regex>)>,
+}
+
+impl ContentFilter {
+    pub fn new(rules: Vec<ContentRule>) -> Result<Self> {
+        let mut filter = Self::default();
+        for rule in rules {
+            filter.insert(rule)?;
+        }
+
+        Ok(filter)
+    }
+
+    pub fn rules(&self) -> Vec<ContentRule> {
+        self.rules.iter().map(|(rule, _)| rule.clone()).collect()
+    }
+
+    /// Add a rule, replacing the rule with the same name
+    pub fn insert(&mut self, rule: ContentRule) -> Result<()> {
+        let regex = match &rule.matches {
+            ContentMatch::Regex { pattern } => Some(Regex::new(pattern)?),
+            _ => None,
+        };
+
+        match self.rules.iter_mut().find(|(r, _)| r.name.eq(&rule.name)) {
+            Some(existing) => *existing = (rule, regex),
+            None => self.rules.push((rule, regex)),
+        }
+
+        Ok(())
+    }
+
+    /// Remove the rule with `name`, returns whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.rules.len();
+        self.rules.retain(|(r, _)| !r.name.eq(name));
+
+        self.rules.len() != count
+    }
+
+    /// First rule the event matches
+    pub fn check(&self, event: &Event) -> Option<&ContentRule> {
+        self.rules
+            .iter()
+            .find(|(rule, regex)| match &rule.matches {
+                ContentMatch::Keyword { keyword } => event
+                    .content
+                    .to_lowercase()
+                    .contains(&keyword.to_lowercase()),
+                ContentMatch::Regex { .. } => {
+                    regex.as_ref().is_some_and(|r| r.is_match(&event.content))
+                }
+                ContentMatch::Hashtag { hashtag } => event.tags.iter().any(|t| {
+                    t.values.first().map(String::as_str) == Some("t")
+                        && t.values.get(1).is_some_and(|v| {
+                            v.eq_ignore_ascii_case(hashtag.trim_start_matches('#'))
+                        })
+                }),
+                ContentMatch::MaxLength { length } => event.content.chars().count() > *length,
+                ContentMatch::MaxTags { count } => event.tags.len() > *count,
+                ContentMatch::Domain { domain } => linked_hosts(&event.content).any(|host| {
+                    let domain = domain.to_lowercase();
+                    host.eq(&domain) || host.ends_with(&format!(".{domain}"))
+                }),
+            })
+            .map(|(rule, _)| rule)
+    }
+}
+
+/// Lowercase hosts of the http(s) urls in `content`
+fn linked_hosts(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .split_whitespace()
+        .filter_map(|word| {
+            word.find("http://")
+                .or(word.find("https://"))
+                .map(|i| &word[i..])
+        })
+        .filter_map(|link| Url::parse(link).ok())
+        .filter_map(|url| url.host_str().map(str::to_lowercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nauthz_grpc::event::TagEntry;
+
+    use super::*;
+
+    fn rule(name: &str, matches: ContentMatch) -> ContentRule {
+        ContentRule {
+            name: name.to_string(),
+            matches,
+            message: format!("blocked: {name}"),
+        }
+    }
+
+    fn event(content: &str, tags: &[&[&str]]) -> Event {
+        Event {
+            id: vec![],
+            pubkey: vec![],
+            created_at: 0,
+            kind: 1,
+            content: content.to_string(),
+            tags: tags
+                .iter()
+                .map(|t| TagEntry {
+                    values: t.iter().map(|v| v.to_string()).collect(),
+                })
+                .collect(),
+            sig: vec![],
+        }
+    }
+
+    fn matched<'a>(filter: &'a ContentFilter, event: &Event) -> Option<&'a str> {
+        filter.check(event).map(|r| r.name.as_str())
+    }
+
+    #[test]
+    fn test_check_keyword_ignores_case() {
+        let filter = ContentFilter::new(vec![rule(
+            "casino",
+            ContentMatch::Keyword {
+                keyword: "Casino".to_string(),
+            },
+        )])
+        .unwrap();
+
+        assert_eq!(
+            matched(&filter, &event("best CASINO bonus", &[])),
+            Some("casino")
+        );
+        assert_eq!(matched(&filter, &event("gm", &[])), None);
+    }
+
+    #[test]
+    fn test_check_regex() {
+        let filter = ContentFilter::new(vec![rule(
+            "phone",
+            ContentMatch::Regex {
+                pattern: r"\d{3}-\d{4}".to_string(),
+            },
+        )])
+        .unwrap();
+
+        assert_eq!(
+            matched(&filter, &event("call 555-1234", &[])),
+            Some("phone")
+        );
+        assert_eq!(matched(&filter, &event("call me", &[])), None);
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected() {
+        let rules = vec![rule(
+            "broken",
+            ContentMatch::Regex {
+                pattern: "(".to_string(),
+            },
+        )];
+
+        assert!(ContentFilter::new(rules).is_err());
+    }
+
+    #[test]
+    fn test_check_hashtag() {
+        let filter = ContentFilter::new(vec![rule(
+            "airdrop",
+            ContentMatch::Hashtag {
+                hashtag: "#Airdrop".to_string(),
+            },
+        )])
+        .unwrap();
+
+        assert_eq!(
+            matched(&filter, &event("", &[&["t", "airdrop"]])),
+            Some("airdrop")
+        );
+        assert_eq!(
+            matched(&filter, &event("#airdrop", &[&["t", "nostr"]])),
+            None
+        );
+        assert_eq!(matched(&filter, &event("", &[&["p", "airdrop"]])), None);
+    }
+
+    #[test]
+    fn test_check_max_length_and_tags() {
+        let filter = ContentFilter::new(vec![
+            rule("long", ContentMatch::MaxLength { length: 5 }),
+            rule("tags", ContentMatch::MaxTags { count: 1 }),
+        ])
+        .unwrap();
+
+        assert_eq!(matched(&filter, &event("héllo", &[&["t", "a"]])), None);
+        assert_eq!(matched(&filter, &event("hello!", &[])), Some("long"));
+        assert_eq!(
+            matched(&filter, &event("", &[&["t", "a"], &["t", "b"]])),
+            Some("tags")
+        );
+    }
+
+    #[test]
+    fn test_check_domain_and_subdomains() {
+        let filter = ContentFilter::new(vec![rule(
+            "spam",
+            ContentMatch::Domain {
+                domain: "Spam.example".to_string(),
+            },
+        )])
+        .unwrap();
+
+        assert_eq!(
+            matched(&filter, &event("see https://spam.example/x", &[])),
+            Some("spam")
+        );
+        assert_eq!(
+            matched(&filter, &event("see (http://WWW.SPAM.EXAMPLE/x)", &[])),
+            Some("spam")
+        );
+        assert_eq!(
+            matched(&filter, &event("https://notspam.example", &[])),
+            None
+        );
+        assert_eq!(matched(&filter, &event("spam.example", &[])), None);
+    }
+
+    #[test]
+    fn test_check_returns_first_matching_rule() {
+        let mut filter = ContentFilter::new(vec![
+            rule(
+                "first",
+                ContentMatch::Keyword {
+                    keyword: "gm".to_string(),
+                },
+            ),
+            rule("second", ContentMatch::MaxLength { length: 0 }),
+        ])
+        .unwrap();
+        assert_eq!(matched(&filter, &event("gm", &[])), Some("first"));
+
+        assert!(filter.remove("first"));
+        assert!(!filter.remove("first"));
+        assert_eq!(matched(&filter, &event("gm", &[])), Some("second"));
+    }
+
+    #[test]
+    fn test_insert_replaces_rule_with_same_name() {
+        let mut filter = ContentFilter::new(vec![rule(
+            "words",
+            ContentMatch::Keyword {
+                keyword: "gm".to_string(),
+            },
+        )])
+        .unwrap();
+        filter
+            .insert(rule(
+                "words",
+                ContentMatch::Keyword {
+                    keyword: "gn".to_string(),
+                },
+            ))
+            .unwrap();
+
+        assert_eq!(filter.rules().len(), 1);
+        assert_eq!(matched(&filter, &event("gm", &[])), None);
+        assert_eq!(matched(&filter, &event("gn", &[])), Some("words"));
+    }
+
+    #[test]
+    fn test_linked_hosts() {
+        let hosts: Vec<_> = linked_hosts(
+            "see https://Example.com/a and (http://sub.test.org:8080/x), not ftp://files.net or example.org",
+        )
+        .collect();
+
+        assert_eq!(hosts, vec!["example.com", "sub.test.org"]);
+    }
+}
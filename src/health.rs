@@ -0,0 +1,110 @@
+//! Readiness of the service and the status of the relays the lists are synced with
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use ::url::Url;
+use nostr_sdk::client::Client;
+use nostr_sdk::relay::RelayStatus;
+use serde::Serialize;
+
+use crate::utils::unix_time;
+
+/// Last observed connection status of a relay
+#[derive(Debug, Clone, Default)]
+struct RelayState {
+    status: Option<RelayStatus>,
+    /// Unix time the lists were last published to or restored from the relay
+    last_sync: Option<u64>,
+}
+
+/// Shared outside of the repo lock so health checks answer while the lists are restored
+#[derive(Debug, Default)]
+pub struct Health {
+    ready: AtomicBool,
+    relays: Mutex<BTreeMap<Url, RelayState>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelayHealth {
+    pub url: String,
+    /// `connected`, `connecting`, `disconnected`... or `unknown` until the relay is first used
+    pub status: String,
+    pub last_sync: Option<u64>,
+    /// Seconds since `last_sync`
+    pub since_last_sync: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub relays: Vec<RelayHealth>,
+}
+
+impl Health {
+    pub fn new(relays: &HashSet<Url>) -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            relays: Mutex::new(
+                relays
+                    .iter()
+                    .map(|url| (url.clone(), RelayState::default()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Mark the service as ready once the lists are restored
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Record the status of the relays of `client` after a sync, connected relays count as synced
+    pub async fn record_sync(&self, client: &Client) {
+        let mut statuses = Vec::new();
+        for (url, relay) in client.relays().await {
+            statuses.push((url, relay.status().await));
+        }
+
+        let now = unix_time();
+        if let Ok(mut relays) = self.relays.lock() {
+            for (url, status) in statuses {
+                let state = relays.entry(url).or_default();
+                if status == RelayStatus::Connected {
+                    state.last_sync = Some(now);
+                }
+                state.status = Some(status);
+            }
+        }
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let now = unix_time();
+        let relays = match self.relays.lock() {
+            Ok(relays) => relays
+                .iter()
+                .map(|(url, state)| RelayHealth {
+                    url: url.to_string(),
+                    status: state
+                        .status
+                        .as_ref()
+                        .map(|s| s.to_string().to_lowercase())
+                        .unwrap_or("unknown".to_string()),
+                    last_sync: state.last_sync,
+                    since_last_sync: state.last_sync.map(|t| now.saturating_sub(t)),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Readiness {
+            ready: self.is_ready(),
+            relays,
+        }
+    }
+}
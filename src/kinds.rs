@@ -0,0 +1,231 @@
+//! Rules deciding who may publish events of a kind
+
+use serde::{Deserialize, Serialize};
+
+/// NIP-01 kind classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KindClass {
+    Regular,
+    Replaceable,
+    Ephemeral,
+    Parameterized,
+}
+
+impl KindClass {
+    pub fn of(kind: u64) -> Self {
+        match kind {
+            0 | 3 | 10_000..=19_999 => Self::Replaceable,
+            20_000..=29_999 => Self::Ephemeral,
+            30_000..=39_999 => Self::Parameterized,
+            _ => Self::Regular,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Regular => "regular",
+            Self::Replaceable => "replaceable",
+            Self::Ephemeral => "ephemeral",
+            Self::Parameterized => "parameterized",
+        }
+    }
+}
+
+/// Inclusive range of kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindRange {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Who may publish events of the kinds a rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KindPolicy {
+    /// Unknown pubkeys may publish, denied pubkeys are still rejected
+    Anyone,
+    /// Only allowed pubkeys may publish, even if `implicit_allow` is set
+    Members,
+    /// Only admins may publish
+    Admins,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindRule {
+    /// Unique name of the rule
+    pub name: String,
+    #[serde(default)]
+    pub kinds: Vec<u64>,
+    #[serde(default)]
+    pub ranges: Vec<KindRange>,
+    #[serde(default)]
+    pub classes: Vec<KindClass>,
+    pub policy: KindPolicy,
+    /// Message returned to clients that are not allowed to publish
+    pub message: Option<String>,
+}
+
+impl KindRule {
+    pub fn matches(&self, kind: u64) -> bool {
+        self.kinds.contains(&kind)
+            || self.ranges.iter().any(|r| (r.from..=r.to).contains(&kind))
+            || self.classes.contains(&KindClass::of(kind))
+    }
+
+    /// Message returned to clients that are not allowed to publish
+    pub fn deny_message(&self) -> String {
+        self.message.clone().unwrap_or(match self.policy {
+            KindPolicy::Admins => "restricted: only admins may publish this kind".to_string(),
+            _ => "restricted: only members may publish this kind".to_string(),
+        })
+    }
+}
+
+/// Kind rules in order of precedence
+#[derive(Debug, Clone, Default)]
+pub struct KindRules {
+    rules: Vec<KindRule>,
+}
+
+impl KindRules {
+    pub fn new(rules: Vec<KindRule>) -> Self {
+        let mut kind_rules = Self::default();
+        for rule in rules {
+            kind_rules.insert(rule);
+        }
+
+        kind_rules
+    }
+
+    pub fn rules(&self) -> Vec<KindRule> {
+        self.rules.clone()
+    }
+
+    /// Add a rule with the lowest precedence, replacing the rule with the same name in place
+    pub fn insert(&mut self, rule: KindRule) {
+        match self.rules.iter_mut().find(|r| r.name.eq(&rule.name)) {
+            Some(existing) => *existing = rule,
+            None => self.rules.push(rule),
+        }
+    }
+
+    /// Remove the rule with `name`, returns whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.rules.len();
+        self.rules.retain(|r| !r.name.eq(name));
+
+        self.rules.len() != count
+    }
+
+    /// First rule matching `kind`
+    pub fn check(&self, kind: u64) -> Option<&KindRule> {
+        self.rules.iter().find(|r| r.matches(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, policy: KindPolicy) -> KindRule {
+        KindRule {
+            name: name.to_string(),
+            kinds: vec![],
+            ranges: vec![],
+            classes: vec![],
+            policy,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_kind_class_of() {
+        assert_eq!(KindClass::of(0), KindClass::Replaceable);
+        assert_eq!(KindClass::of(1), KindClass::Regular);
+        assert_eq!(KindClass::of(3), KindClass::Replaceable);
+        assert_eq!(KindClass::of(4), KindClass::Regular);
+        assert_eq!(KindClass::of(9_999), KindClass::Regular);
+        assert_eq!(KindClass::of(10_000), KindClass::Replaceable);
+        assert_eq!(KindClass::of(19_999), KindClass::Replaceable);
+        assert_eq!(KindClass::of(20_000), KindClass::Ephemeral);
+        assert_eq!(KindClass::of(29_999), KindClass::Ephemeral);
+        assert_eq!(KindClass::of(30_000), KindClass::Parameterized);
+        assert_eq!(KindClass::of(39_999), KindClass::Parameterized);
+        assert_eq!(KindClass::of(40_000), KindClass::Regular);
+    }
+
+    #[test]
+    fn test_check_matches_kinds_ranges_and_classes() {
+        let rules = KindRules::new(vec![
+            KindRule {
+                kinds: vec![1984],
+                ..rule("reports", KindPolicy::Members)
+            },
+            KindRule {
+                ranges: vec![KindRange {
+                    from: 5000,
+                    to: 5999,
+                }],
+                ..rule("dvm", KindPolicy::Admins)
+            },
+            KindRule {
+                classes: vec![KindClass::Ephemeral],
+                ..rule("ephemeral", KindPolicy::Anyone)
+            },
+        ]);
+
+        let matched = |kind| rules.check(kind).map(|r| r.name.as_str());
+        assert_eq!(matched(1984), Some("reports"));
+        assert_eq!(matched(5000), Some("dvm"));
+        assert_eq!(matched(5999), Some("dvm"));
+        assert_eq!(matched(6000), None);
+        assert_eq!(matched(20_001), Some("ephemeral"));
+        assert_eq!(matched(1), None);
+    }
+
+    #[test]
+    fn test_check_returns_first_matching_rule() {
+        let mut rules = KindRules::new(vec![
+            KindRule {
+                kinds: vec![7],
+                ..rule("reactions", KindPolicy::Anyone)
+            },
+            KindRule {
+                classes: vec![KindClass::Regular],
+                ..rule("regular", KindPolicy::Members)
+            },
+        ]);
+        assert_eq!(rules.check(7).unwrap().policy, KindPolicy::Anyone);
+        assert_eq!(rules.check(1).unwrap().policy, KindPolicy::Members);
+
+        rules.insert(KindRule {
+            kinds: vec![1],
+            ..rule("reactions", KindPolicy::Admins)
+        });
+        assert_eq!(rules.rules().len(), 2);
+        assert_eq!(rules.check(1).unwrap().name, "reactions");
+        assert_eq!(rules.check(7).unwrap().name, "regular");
+
+        assert!(rules.remove("reactions"));
+        assert!(!rules.remove("reactions"));
+        assert_eq!(rules.check(1).unwrap().name, "regular");
+    }
+
+    #[test]
+    fn test_deny_message() {
+        assert_eq!(
+            rule("admins", KindPolicy::Admins).deny_message(),
+            "restricted: only admins may publish this kind"
+        );
+        assert_eq!(
+            rule("members", KindPolicy::Members).deny_message(),
+            "restricted: only members may publish this kind"
+        );
+        let custom = KindRule {
+            message: Some("blocked: no long form".to_string()),
+            ..rule("custom", KindPolicy::Members)
+        };
+        assert_eq!(custom.deny_message(), "blocked: no long form");
+    }
+}
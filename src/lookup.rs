@@ -0,0 +1,159 @@
+//! Lookup of the authors of referenced events on the configured relays
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::url::Url;
+use anyhow::Result;
+use nostr_sdk::client::Client;
+use nostr_sdk::key::{Keys, XOnlyPublicKey};
+use nostr_sdk::Filter;
+use tokio::sync::OnceCell;
+
+/// How long a looked up author is reused
+const CACHE_TTL: Duration = Duration::from_secs(600);
+/// How long an event that was not found is not looked up again
+const NOT_FOUND_TTL: Duration = Duration::from_secs(60);
+/// Events looked up on the relays per call, the rest are left out
+const MAX_LOOKUPS: usize = 20;
+/// Authors cached before expired entries are dropped
+const CACHE_SIZE: usize = 10_000;
+
+/// Shared outside of the repo lock so lookups do not hold it while waiting on the relays
+#[derive(Debug)]
+pub struct EventAuthors {
+    key: Keys,
+    relays: HashSet<Url>,
+    /// Connected on the first lookup and kept open
+    client: OnceCell<Client>,
+    /// Author of each looked up event id, `None` if it was not found, with the time it was looked up
+    cache: Mutex<HashMap<String, (Option<XOnlyPublicKey>, Instant)>>,
+}
+
+impl EventAuthors {
+    pub fn new(key: Keys, relays: HashSet<Url>) -> Self {
+        Self {
+            key,
+            relays,
+            client: OnceCell::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn client(&self) -> Result<&Client> {
+        self.client
+            .get_or_try_init(|| async {
+                let relays = self.relays.iter().map(|x| (x.to_string(), None)).collect();
+
+                let client = Client::new(&self.key);
+                client.add_relays(relays).await?;
+                client.connect().await;
+
+                Ok(client)
+            })
+            .await
+    }
+
+    /// Authors of the events with `ids`, events not found on the relays are left out
+    ///
+    /// At most `MAX_LOOKUPS` uncached events are looked up, the others are left out as well.
+    pub async fn fetch(&self, ids: Vec<String>) -> Result<HashMap<String, XOnlyPublicKey>> {
+        let (mut authors, missing) = self.cached(ids, Instant::now());
+
+        if missing.is_empty() {
+            return Ok(authors);
+        }
+
+        let subscription = Filter::new().ids(missing.clone());
+
+        let timeout = Duration::from_secs(5);
+        let events = self
+            .client()
+            .await?
+            .get_events_of(vec![subscription], Some(timeout))
+            .await?;
+
+        let now = Instant::now();
+        if let Ok(mut cache) = self.cache.lock() {
+            if cache.len() > CACHE_SIZE {
+                cache.retain(|_, (_, at)| at.elapsed() < CACHE_TTL);
+            }
+            for event in events {
+                let id = event.id.to_hex();
+                cache.insert(id.clone(), (Some(event.pubkey), now));
+                authors.insert(id, event.pubkey);
+            }
+            for id in missing {
+                cache.entry(id).or_insert((None, now));
+            }
+        }
+
+        Ok(authors)
+    }
+
+    /// Cached authors of the events with `ids` at `now` and the ids still to look up, at most `MAX_LOOKUPS`
+    fn cached(
+        &self,
+        ids: Vec<String>,
+        now: Instant,
+    ) -> (HashMap<String, XOnlyPublicKey>, Vec<String>) {
+        let mut authors = HashMap::new();
+        let mut missing = Vec::new();
+
+        if let Ok(cache) = self.cache.lock() {
+            for id in ids {
+                match cache.get(&id) {
+                    Some((Some(author), at)) if now.duration_since(*at) < CACHE_TTL => {
+                        authors.insert(id, *author);
+                    }
+                    Some((None, at)) if now.duration_since(*at) < NOT_FOUND_TTL => (),
+                    _ if missing.len() < MAX_LOOKUPS && !missing.contains(&id) => missing.push(id),
+                    _ => (),
+                }
+            }
+        }
+
+        (authors, missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(i: usize) -> String {
+        format!("{i:064x}")
+    }
+
+    #[test]
+    fn test_cached_skips_events_not_found_until_they_expire() {
+        let authors = EventAuthors::new(Keys::generate(), HashSet::new());
+        let author = Keys::generate().public_key();
+        let start = Instant::now();
+        let now = start + CACHE_TTL;
+        {
+            let mut cache = authors.cache.lock().unwrap();
+            cache.insert(id(1), (Some(author), now));
+            cache.insert(id(2), (None, now));
+            cache.insert(id(3), (None, now - NOT_FOUND_TTL));
+            cache.insert(id(4), (Some(author), start));
+        }
+
+        let (found, missing) = authors.cached((1..=5).map(id).collect(), now);
+
+        assert_eq!(found, HashMap::from([(id(1), author)]));
+        assert_eq!(missing, vec![id(3), id(4), id(5)]);
+    }
+
+    #[test]
+    fn test_cached_caps_lookups() {
+        let authors = EventAuthors::new(Keys::generate(), HashSet::new());
+        let mut ids: Vec<String> = (0..MAX_LOOKUPS * 2).map(id).collect();
+        ids.insert(1, id(0));
+
+        let (_, missing) = authors.cached(ids, Instant::now());
+
+        assert_eq!(missing, (0..MAX_LOOKUPS).map(id).collect::<Vec<_>>());
+    }
+}
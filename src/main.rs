@@ -1,57 +1,308 @@
-use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::http::HeaderMap;
-use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    routing::{get, post},
-    Router,
-};
 use clap::Parser;
+use nauthz_admin::user_admin_server::UserAdminServer;
 use nauthz_grpc::authorization_server::{Authorization, AuthorizationServer};
 use nauthz_grpc::{Decision, EventReply, EventRequest};
 use nostr_sdk::key::XOnlyPublicKey;
-use nostr_sdk::prelude::FromSkStr;
+use nostr_sdk::prelude::{FromSkStr, ToBech32};
 use nostr_sdk::Keys;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, MutexGuard};
 use tokio::task;
 use tonic::{transport::Server, Request, Response, Status};
 use tracing::{debug, info};
 
+use crate::admin::Admin;
+use crate::api::start_server;
+use crate::audit::{AuditLog, DecisionRecord};
+use crate::auth::{hash_secret, ApiKey, GrpcToken, Scope};
 use crate::cli::CLIArgs;
 use crate::config::Settings;
+use crate::kinds::{KindPolicy, KindRule};
+use crate::metrics::Metrics;
 use crate::repo::Repo;
+use crate::tls::TlsFiles;
 
 pub mod nauthz_grpc {
     tonic::include_proto!("nauthz");
 }
 
+pub mod nauthz_admin {
+    tonic::include_proto!("nauthz.admin");
+}
+
+pub mod admin;
+pub mod api;
+pub mod audit;
+pub mod auth;
 pub mod cli;
 pub mod config;
+pub mod content;
+pub mod health;
+pub mod kinds;
+pub mod lookup;
+pub mod metrics;
+pub mod publisher;
 pub mod repo;
+pub mod tls;
 pub mod utils;
 
+#[derive(Clone)]
 pub struct EventAuthz {
     pub pubkey: XOnlyPublicKey,
     pub repo: Arc<Mutex<Repo>>,
     pub settings: Settings,
+    pub metrics: Arc<Metrics>,
+    /// Log of the decisions, if enabled
+    pub audit: Option<Arc<AuditLog>>,
+}
+
+/// Rule an event was decided by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecisionReason {
+    /// Published with the relay key
+    RelayKey,
+    Admin,
+    Allowed,
+    Denied,
+    ImplicitAllow,
+    Blocked,
+    /// Content rule with its name
+    ContentRule(String),
+    DeniedReference,
+    /// Kind rule with its name
+    KindRule(String),
+    Report,
+    SelfDeletion,
+    Inbound,
+    RateLimited,
+    Pow,
+    /// Unknown pubkey not admitted by any rule
+    Unknown,
+}
+
+impl DecisionReason {
+    /// Name of the reason without rule names, to keep metric labels bounded
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::RelayKey => "relay_key",
+            Self::Admin => "admin",
+            Self::Allowed => "allowed",
+            Self::Denied => "denied",
+            Self::ImplicitAllow => "implicit_allow",
+            Self::Blocked => "blocked",
+            Self::ContentRule(_) => "content_rule",
+            Self::DeniedReference => "denied_reference",
+            Self::KindRule(_) => "kind_rule",
+            Self::Report => "report",
+            Self::SelfDeletion => "self_deletion",
+            Self::Inbound => "inbound",
+            Self::RateLimited => "rate_limited",
+            Self::Pow => "pow",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Name of the content or kind rule the event matched
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            Self::ContentRule(name) | Self::KindRule(name) => Some(name),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum UserStatus {
     Allowed,
     Denied,
     Unknown,
 }
 
-#[tonic::async_trait]
-impl Authorization for EventAuthz {
-    async fn event_admit(
+/// Reply denying an event if its kind rule restricts the kind to admins or members
+fn kind_restriction(
+    rule: Option<&KindRule>,
+    is_admin: bool,
+    status: UserStatus,
+) -> Option<(EventReply, DecisionReason)> {
+    let rule = rule?;
+    let restricted = match rule.policy {
+        KindPolicy::Anyone => false,
+        KindPolicy::Members => !is_admin && !status.eq(&UserStatus::Allowed),
+        KindPolicy::Admins => !is_admin,
+    };
+
+    restricted.then(|| {
+        (
+            nauthz_grpc::EventReply {
+                decision: Decision::Deny as i32,
+                message: Some(rule.deny_message()),
+            },
+            DecisionReason::KindRule(rule.name.clone()),
+        )
+    })
+}
+
+impl EventAuthz {
+    /// Decision based only on the status of the author and the kind rule of the event
+    ///
+    /// Returns `None` for unknown authors that may still be admitted by the inbound or proof of work rules.
+    fn status_decision(
         &self,
-        request: Request<EventRequest>,
-    ) -> Result<Response<EventReply>, Status> {
-        let req = request.into_inner();
+        is_admin: bool,
+        status: UserStatus,
+        kind_rule: Option<&KindRule>,
+        note: Option<&str>,
+    ) -> Option<(EventReply, DecisionReason)> {
+        if let Some(decided) = kind_restriction(kind_rule, is_admin, status) {
+            return Some(decided);
+        }
+
+        let public = kind_rule.filter(|r| r.policy.eq(&KindPolicy::Anyone));
+
+        let permit = nauthz_grpc::EventReply {
+            decision: Decision::Permit as i32,
+            message: Some("Ok".to_string()),
+        };
+
+        match status {
+            _ if is_admin => Some((permit, DecisionReason::Admin)),
+            UserStatus::Allowed => Some((permit, DecisionReason::Allowed)),
+            UserStatus::Denied => Some((
+                nauthz_grpc::EventReply {
+                    decision: Decision::Deny as i32,
+                    message: Some(match note {
+                        Some(note) if self.settings.info.deny_reason_in_message => {
+                            format!("Not allowed to publish: {note}")
+                        }
+                        _ => "Not allowed to publish".to_string(),
+                    }),
+                },
+                DecisionReason::Denied,
+            )),
+            UserStatus::Unknown if self.settings.info.implicit_allow => {
+                Some((permit, DecisionReason::ImplicitAllow))
+            }
+            UserStatus::Unknown => {
+                public.map(|rule| (permit, DecisionReason::KindRule(rule.name.clone())))
+            }
+        }
+    }
+
+    /// Decision `event_admit` would make for an event of `kind` from `pubkey` without content, tags or proof of work
+    pub async fn preview_decision(&self, pubkey: XOnlyPublicKey, kind: u64) -> EventReply {
+        if self.pubkey.eq(&pubkey) {
+            return nauthz_grpc::EventReply {
+                decision: Decision::Permit as i32,
+                message: Some("Ok".to_string()),
+            };
+        }
+
+        let (status, kind_rule, note) = {
+            let repo = self.lock_repo().await;
+            (
+                repo.get_user_status(pubkey).await,
+                repo.kind_rules.check(kind).cloned(),
+                repo.get_record(&pubkey).and_then(|r| r.note),
+            )
+        };
+        let is_admin = self.settings.info.admins.contains(&pubkey);
+
+        if let Some((reply, _)) =
+            self.status_decision(is_admin, status, kind_rule.as_ref(), note.as_deref())
+        {
+            return reply;
+        }
+
+        let message = match self.settings.pow.required_difficulty(kind) {
+            Some(difficulty) => format!("pow: difficulty {difficulty} required"),
+            None => "Not allowed to publish".to_string(),
+        };
+
+        nauthz_grpc::EventReply {
+            decision: Decision::Deny as i32,
+            message: Some(message),
+        }
+    }
+
+    /// Whether the event tags, mentions or replies to a denied pubkey
+    async fn references_denied(&self, event: &nauthz_grpc::Event) -> bool {
+        let mut pubkeys = utils::tagged_pubkeys(&event.tags);
+        pubkeys.extend(utils::event_tag_authors(&event.tags));
+        pubkeys.extend(utils::mentioned_pubkeys(&event.content));
+
+        if self.lock_repo().await.any_denied(&pubkeys) {
+            return true;
+        }
+
+        if !self.settings.denied_references.lookup_event_authors {
+            return false;
+        }
+
+        let (ids, _) = utils::referenced_events(&event.tags);
+        if ids.is_empty() {
+            return false;
+        }
+
+        let event_authors = self.lock_repo().await.event_authors.clone();
+
+        match event_authors.fetch(ids).await {
+            Ok(authors) => self
+                .lock_repo()
+                .await
+                .any_denied(&authors.into_values().collect()),
+            Err(err) => {
+                log::warn!("Could not fetch referenced events: {err}");
+                false
+            }
+        }
+    }
+
+    /// Whether every event referenced by a deletion was published by `author`
+    async fn deletes_own_events(&self, author: XOnlyPublicKey, event: &nauthz_grpc::Event) -> bool {
+        let (ids, coordinates) = utils::referenced_events(&event.tags);
+        if ids.is_empty() && coordinates.is_empty() {
+            return false;
+        }
+
+        let author_hex = author.to_string();
+        if !coordinates
+            .iter()
+            .all(|c| c.split(':').nth(1).eq(&Some(author_hex.as_str())))
+        {
+            return false;
+        }
+
+        if ids.is_empty() {
+            return true;
+        }
+
+        let event_authors = self.lock_repo().await.event_authors.clone();
+
+        match event_authors.fetch(ids.clone()).await {
+            Ok(authors) => ids.iter().all(|id| authors.get(id).eq(&Some(&author))),
+            Err(err) => {
+                log::warn!("Could not fetch deleted events: {err}");
+                false
+            }
+        }
+    }
+
+    /// Lock the repo, recording how long the lock was waited for
+    async fn lock_repo(&self) -> MutexGuard<'_, Repo> {
+        let start = Instant::now();
+        let repo = self.repo.lock().await;
+        self.metrics.observe_lock_wait(start.elapsed());
+
+        repo
+    }
+
+    /// Decide whether to admit the event of `req` and the rule the decision was made by
+    async fn decide(&self, req: &EventRequest) -> Result<(EventReply, DecisionReason), Status> {
         let event = req.clone().event.ok_or(Status::not_found(""))?;
         let content_prefix: String = event.content.chars().take(40).collect();
         info!("recvd event, [kind={}, origin={:?}, nip05_domain={:?}, tag_count={}, content_sample={:?}]",
@@ -72,45 +323,286 @@ impl Authorization for EventAuthz {
                 .kind
                 .eq(&nostr_sdk::Kind::CategorizedPeopleList.as_u64())
             {
-                self.repo
-                    .lock()
+                self.lock_repo()
                     .await
                     .update_people(event)
                     .await
                     .map_err(|_| Status::internal("Could not update users"))?;
             }
 
-            return Ok(Response::new(nauthz_grpc::EventReply {
-                decision: Decision::Permit as i32,
-                message: Some("Ok".to_string()),
-            }));
+            return Ok((
+                nauthz_grpc::EventReply {
+                    decision: Decision::Permit as i32,
+                    message: Some("Ok".to_string()),
+                },
+                DecisionReason::RelayKey,
+            ));
         }
 
-        let response = match self.repo.lock().await.get_user_status(author).await {
-            UserStatus::Allowed => Response::new(nauthz_grpc::EventReply {
-                decision: Decision::Permit as i32,
-                message: Some("Ok".to_string()),
-            }),
-            UserStatus::Denied => Response::new(nauthz_grpc::EventReply {
-                decision: Decision::Deny as i32,
-                message: Some("Not allowed to publish".to_string()),
-            }),
-            UserStatus::Unknown => {
-                if self.settings.info.implicit_allow {
-                    return Ok(Response::new(nauthz_grpc::EventReply {
+        let is_admin = self.settings.info.admins.contains(&author);
+
+        if !is_admin {
+            if self.lock_repo().await.is_blocked(&event) {
+                debug!("Event is blocked");
+                return Ok((
+                    nauthz_grpc::EventReply {
+                        decision: Decision::Deny as i32,
+                        message: Some("blocked: event is not allowed".to_string()),
+                    },
+                    DecisionReason::Blocked,
+                ));
+            }
+
+            if let Some(rule) = self.lock_repo().await.content_filter.check(&event) {
+                debug!("Event matched content rule {}", rule.name);
+                return Ok((
+                    nauthz_grpc::EventReply {
+                        decision: Decision::Deny as i32,
+                        message: Some(rule.message.clone()),
+                    },
+                    DecisionReason::ContentRule(rule.name.clone()),
+                ));
+            }
+
+            let denied_references = &self.settings.denied_references;
+            if denied_references.enabled
+                && (denied_references.kinds.is_empty()
+                    || denied_references.kinds.contains(&event.kind))
+                && self.references_denied(&event).await
+            {
+                debug!("Event references a denied pubkey");
+                return Ok((
+                    nauthz_grpc::EventReply {
+                        decision: Decision::Deny as i32,
+                        message: Some(
+                            denied_references
+                                .message
+                                .clone()
+                                .unwrap_or("blocked: references a denied user".to_string()),
+                        ),
+                    },
+                    DecisionReason::DeniedReference,
+                ));
+            }
+        }
+
+        let status = self.lock_repo().await.get_user_status(author).await;
+
+        // Kind rules can restrict who may publish a kind regardless of membership
+        let kind_rule = self.lock_repo().await.kind_rules.check(event.kind).cloned();
+        if let Some(decided) = kind_restriction(kind_rule.as_ref(), is_admin, status) {
+            debug!(
+                "Event restricted by kind rule {:?}",
+                kind_rule.map(|r| r.name)
+            );
+            return Ok(decided);
+        }
+
+        // Reports from members and trusted reporters are tallied
+        if self.settings.reports.enabled
+            && event.kind.eq(&nostr_sdk::Kind::Reporting.as_u64())
+            && (status.eq(&UserStatus::Allowed)
+                || self.settings.reports.trusted_reporters.contains(&author))
+        {
+            let reports = utils::reported_pubkeys(&event.tags);
+            let mut exempt = self.settings.info.admins.clone();
+            exempt.insert(self.pubkey);
+
+            let (banned, events, publisher) = {
+                let mut repo = self.lock_repo().await;
+                let (banned, events) = repo
+                    .report_pubkeys(author, &reports, &self.settings.reports, &exempt)
+                    .map_err(|_| Status::internal("Could not record reports"))?;
+                (banned, events, repo.publisher.clone())
+            };
+            publisher
+                .publish_all(events)
+                .await
+                .map_err(|_| Status::internal("Could not record reports"))?;
+
+            for (pubkey, report_type) in banned {
+                let pubkey = pubkey.to_bech32().unwrap_or(pubkey.to_string());
+                info!("Denied {pubkey} after {report_type} reports");
+
+                let message = match self.settings.reports.ban_duration {
+                    Some(duration) => format!(
+                        "Denied {pubkey} for {duration} seconds after reaching the {report_type} report threshold"
+                    ),
+                    None => format!(
+                        "Denied {pubkey} after reaching the {report_type} report threshold"
+                    ),
+                };
+                if let Err(err) = publisher
+                    .notify_admins(&self.settings.info.admins, &message)
+                    .await
+                {
+                    log::warn!("Could not notify admins: {err}");
+                }
+            }
+
+            return Ok((
+                nauthz_grpc::EventReply {
+                    decision: Decision::Permit as i32,
+                    message: Some("Ok".to_string()),
+                },
+                DecisionReason::Report,
+            ));
+        }
+
+        // Members can invite new users up to their quota
+        if let Some(invite_kind) = self.settings.invites.kind {
+            if status.eq(&UserStatus::Allowed) && event.kind.eq(&invite_kind) {
+                let invitees = utils::tagged_pubkeys(&event.tags);
+                let (invited, events, publisher) = {
+                    let mut repo = self.lock_repo().await;
+                    let (invited, events) = repo
+                        .invite_pubkeys(author, &invitees, self.settings.invites.quota)
+                        .map_err(|_| Status::internal("Could not invite users"))?;
+                    (invited, events, repo.publisher.clone())
+                };
+                publisher
+                    .publish_all(events)
+                    .await
+                    .map_err(|_| Status::internal("Could not invite users"))?;
+                debug!("{author} invited {invited:?}");
+            }
+        }
+
+        // Members can vouch for unknown pubkeys
+        if let Some(vouch_kind) = self.settings.vouches.kind {
+            if status.eq(&UserStatus::Allowed) && event.kind.eq(&vouch_kind) {
+                let candidates = utils::tagged_pubkeys(&event.tags);
+                let (promoted, events, publisher) = {
+                    let mut repo = self.lock_repo().await;
+                    let (promoted, events) = repo
+                        .vouch_pubkeys(author, &candidates, self.settings.vouches.threshold)
+                        .map_err(|_| Status::internal("Could not record vouches"))?;
+                    (promoted, events, repo.publisher.clone())
+                };
+                publisher
+                    .publish_all(events)
+                    .await
+                    .map_err(|_| Status::internal("Could not record vouches"))?;
+                debug!("{author} vouched for {candidates:?}, admitted {promoted:?}");
+            }
+        }
+
+        // Denied and unknown pubkeys can delete their own events
+        if self.settings.info.allow_self_deletion
+            && event.kind.eq(&nostr_sdk::Kind::EventDeletion.as_u64())
+            && !status.eq(&UserStatus::Allowed)
+            && self.deletes_own_events(author, &event).await
+        {
+            return Ok((
+                nauthz_grpc::EventReply {
+                    decision: Decision::Permit as i32,
+                    message: Some("Ok".to_string()),
+                },
+                DecisionReason::SelfDeletion,
+            ));
+        }
+
+        let note = self
+            .lock_repo()
+            .await
+            .get_record(&author)
+            .and_then(|r| r.note);
+        if let Some(decided) =
+            self.status_decision(is_admin, status, kind_rule.as_ref(), note.as_deref())
+        {
+            return Ok(decided);
+        }
+
+        let inbound = self.settings.inbound.enabled
+            && self.settings.inbound.kinds.contains(&event.kind)
+            && self
+                .lock_repo()
+                .await
+                .addresses_member(&utils::tagged_pubkeys(&event.tags));
+
+        let decided = if inbound {
+            // Unknown pubkeys can send events addressed to members
+            let within_limit = match self.settings.inbound.rate_limit {
+                Some(limit) => self.lock_repo().await.record_inbound(author, limit),
+                None => true,
+            };
+
+            if within_limit {
+                (
+                    nauthz_grpc::EventReply {
+                        decision: Decision::Permit as i32,
+                        message: Some("Ok".to_string()),
+                    },
+                    DecisionReason::Inbound,
+                )
+            } else {
+                (
+                    nauthz_grpc::EventReply {
+                        decision: Decision::Deny as i32,
+                        message: Some("rate-limited: slow down".to_string()),
+                    },
+                    DecisionReason::RateLimited,
+                )
+            }
+        } else if let Some(difficulty) = self.settings.pow.required_difficulty(event.kind) {
+            // Unknown pubkeys can publish events with enough proof of work
+            let reply =
+                if utils::pow_difficulty(&event, self.settings.pow.require_nonce) >= difficulty {
+                    nauthz_grpc::EventReply {
                         decision: Decision::Permit as i32,
                         message: Some("Ok".to_string()),
-                    }));
+                    }
                 } else {
-                    Response::new(nauthz_grpc::EventReply {
+                    nauthz_grpc::EventReply {
                         decision: Decision::Deny as i32,
-                        message: Some("Not allowed to publish".to_string()),
-                    })
+                        message: Some(format!("pow: difficulty {difficulty} required")),
+                    }
+                };
+            (reply, DecisionReason::Pow)
+        } else {
+            (
+                nauthz_grpc::EventReply {
+                    decision: Decision::Deny as i32,
+                    message: Some("Not allowed to publish".to_string()),
+                },
+                DecisionReason::Unknown,
+            )
+        };
+
+        Ok(decided)
+    }
+}
+
+#[tonic::async_trait]
+impl Authorization for EventAuthz {
+    async fn event_admit(
+        &self,
+        request: Request<EventRequest>,
+    ) -> Result<Response<EventReply>, Status> {
+        let start = Instant::now();
+        let req = request.into_inner();
+        let kind = req.event.as_ref().map(|e| e.kind);
+
+        let decided = self.decide(&req).await;
+        self.metrics.observe_decision(
+            decided
+                .as_ref()
+                .ok()
+                .map(|(reply, reason)| (reply.decision(), reason)),
+            kind,
+            start.elapsed(),
+        );
+
+        if let (Some(audit), Ok((reply, reason))) = (&self.audit, &decided) {
+            // Only sampled events pay for building the record
+            if req.event.as_ref().is_some_and(|e| audit.sampled(&e.id)) {
+                if let Some(record) = DecisionRecord::new(&req, reply, reason) {
+                    audit.record(record);
                 }
             }
-        };
+        }
 
-        Ok(response)
+        decided.map(|(reply, _)| Response::new(reply))
     }
 }
 
@@ -136,142 +628,486 @@ async fn main() -> anyhow::Result<()> {
 
     let mut repo = Repo::new(keys.clone(), settings.info.relays.clone())?;
 
-    repo.restore_user_list().await?;
+    repo.set_content_rules(settings.content_rules.clone())?;
+    repo.set_kind_rules(settings.kind_rules.clone());
+    if let Some(size) = settings.info.change_log_size {
+        repo.change_log_size = size;
+    }
 
+    // The single `api_key` is kept as an admin key with the id `default`
+    if let Some(api_key) = &settings.info.api_key {
+        repo.insert_api_key(ApiKey {
+            id: "default".to_string(),
+            hash: hash_secret(api_key),
+            scope: Scope::Admin,
+            expires_at: None,
+            from_config: true,
+        });
+    }
+    for key in &settings.api_keys {
+        repo.insert_api_key(ApiKey {
+            from_config: true,
+            ..key.clone()
+        });
+    }
+
+    let health = repo.health.clone();
+    let metrics = repo.metrics.clone();
+    let audit = if settings.audit.enabled {
+        Some(Arc::new(AuditLog::new(&settings.audit).await?))
+    } else {
+        None
+    };
     let repo = Arc::new(Mutex::new(repo));
 
+    // Hold the lock until the lists are restored so no event is admitted against empty lists,
+    // while the servers already answer health checks
+    let mut restoring = repo.clone().lock_owned().await;
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_not_serving::<AuthorizationServer<EventAuthz>>()
+        .await;
+
+    // Lift time limited denials once they expire
+    if settings.reports.ban_duration.is_some() {
+        let repo = repo.clone();
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let expired = {
+                    let mut repo = repo.lock().await;
+                    repo.expire_denials(utils::unix_time())
+                        .map(|(expired, events)| (expired, events, repo.publisher.clone()))
+                };
+                match expired {
+                    Ok((expired, events, publisher)) if !expired.is_empty() => {
+                        info!("Denial expired for {expired:?}");
+                        if let Err(err) = publisher.publish_all(events).await {
+                            log::warn!("Could not publish expired denials: {err}");
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(err) => log::warn!("Could not expire denials: {err}"),
+                }
+            }
+        });
+    }
+
     let checker = EventAuthz {
         pubkey: keys.public_key(),
         repo: repo.clone(),
         settings: settings.clone(),
+        metrics,
+        audit,
     };
+    let repo_admin = repo.clone();
 
-    // run this in a new thread
-    if let Some(api_key) = settings.info.api_key {
-        let port = settings.info.api_listen_port.unwrap_or(3000);
-        let host = settings
-            .info
-            .api_listen_host
-            .unwrap_or("127.0.0.1".to_string());
+    // Fail on start rather than when serving if TLS is misconfigured
+    let api_tls = TlsFiles::from_settings(
+        &settings.info.api_tls_cert,
+        &settings.info.api_tls_key,
+        &None,
+    )?;
+    let grpc_tls = TlsFiles::from_settings(
+        &settings.info.grpc_tls_cert,
+        &settings.info.grpc_tls_key,
+        &settings.grpc_auth.tls_client_ca,
+    )?;
 
-        task::spawn(async move {
-            if let Err(err) = start_server(api_key, &host.clone(), port, repo).await {
-                log::warn!("{}", err);
-            }
-        });
-    }
+    // The http server always serves health checks and metrics, the management api needs a way to authenticate
+    let management = settings.info.api_key.is_some()
+        || !settings.api_keys.is_empty()
+        || settings.info.nip98_auth;
+    let port = settings.info.api_listen_port.unwrap_or(3000);
+    let host = settings
+        .info
+        .api_listen_host
+        .unwrap_or("127.0.0.1".to_string());
+    let authz = checker.clone();
+    let api_health = health.clone();
+    // run this in a new thread
+    task::spawn(async move {
+        if let Err(err) =
+            start_server(&host, port, repo, authz, api_health, api_tls, management).await
+        {
+            log::warn!("{}", err);
+        }
+    });
 
     info!("EventAuthz Server listening on {addr}");
 
-    // Start serving
-    Server::builder()
-        .add_service(AuthorizationServer::new(checker))
-        .serve(addr)
-        .await?;
+    let admin = settings.grpc_admin.enabled.then(|| {
+        info!("UserAdmin service enabled");
+        UserAdminServer::new(Admin { repo: repo_admin })
+    });
 
-    Ok(())
-}
+    let token = GrpcToken::new(settings.grpc_auth.token.clone());
 
-#[derive(Clone)]
-struct AppState {
-    api_key: String,
-    repo: Arc<Mutex<Repo>>,
-}
+    let router = Server::builder()
+        .add_service(health_service)
+        .add_service(AuthorizationServer::with_interceptor(checker, token))
+        .add_optional_service(admin);
+
+    let restore = async move {
+        restoring.restore_user_list().await?;
+        drop(restoring);
+
+        health.set_ready();
+        health_reporter
+            .set_serving::<AuthorizationServer<EventAuthz>>()
+            .await;
+        info!("Restored the allow and deny lists");
 
-async fn start_server(
-    api_key: String,
-    host: &str,
-    port: u16,
-    repo: Arc<Mutex<Repo>>,
-) -> anyhow::Result<()> {
-    let shared_state = AppState {
-        api_key: api_key.to_string(),
-        repo,
+        Ok::<(), anyhow::Error>(())
     };
 
-    // build our application with a single route
-    let app = Router::new()
-        .route("/update", post(update_users))
-        .route("/users", get(get_users))
-        .with_state(shared_state);
+    // Start serving
+    let serve = async move {
+        match grpc_tls {
+            Some(files) => {
+                info!("Serving grpc over TLS");
+                let listener = TcpListener::bind(addr).await?;
+                router
+                    .serve_with_incoming(tls::incoming(listener, files)?)
+                    .await?;
+            }
+            None => router.serve(addr).await?,
+        }
 
-    let server_add = format!("{}:{}", host, port).parse()?;
+        Ok::<(), anyhow::Error>(())
+    };
 
-    // run it with hyper on localhost:3000
-    axum::Server::bind(&server_add)
-        .serve(app.into_make_service())
-        .await?;
+    tokio::try_join!(restore, serve)?;
 
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Users {
-    allow: Option<HashSet<XOnlyPublicKey>>,
-    deny: Option<HashSet<XOnlyPublicKey>>,
-}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
 
-async fn update_users(
-    headers: HeaderMap,
-    State(state): State<AppState>,
-    Json(payload): Json<Users>,
-) -> Result<(), (StatusCode, String)> {
-    debug!("Users: {payload:?}");
-    if let Some(key) = headers.get("X-Api-Key") {
-        debug!("Sent key: {key:?}");
-        if key.eq(&state.api_key) {
-            // Admit pubkeys
-            if let Some(pubkeys) = &payload.allow {
-                debug!("Pubkeys to allow: {pubkeys:?}");
-                state
-                    .repo
-                    .lock()
-                    .await
-                    .admit_pubkeys(pubkeys)
-                    .await
-                    .map_err(|_| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Could not get admitted pubkeys".to_string(),
-                        )
-                    })?;
-            }
+    use crate::config::KindDifficulty;
+    use crate::kinds::KindPolicy;
+    use crate::nauthz_grpc::event::TagEntry;
 
-            // Deny pubkeys
-            if let Some(pubkeys) = &payload.deny {
-                debug!("Pubkeys to deny: {pubkeys:?}");
-                state
-                    .repo
-                    .lock()
-                    .await
-                    .deny_pubkeys(pubkeys)
-                    .await
-                    .map_err(|_| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Could not get denied pubkeys".to_string(),
-                        )
-                    })?;
-            }
-            return Ok(());
+    use super::*;
+
+    fn authz(settings: Settings) -> EventAuthz {
+        let keys = Keys::generate();
+        let repo = Repo::new(keys.clone(), HashSet::new()).unwrap();
+
+        EventAuthz {
+            pubkey: keys.public_key(),
+            metrics: repo.metrics.clone(),
+            repo: Arc::new(Mutex::new(repo)),
+            settings,
+            audit: None,
         }
     }
 
-    Err((StatusCode::UNAUTHORIZED, "".to_string()))
-}
+    /// Request for an event whose id has `zeros` leading zero bits
+    fn request(author: XOnlyPublicKey, kind: u64, tags: &[&[&str]], zeros: u8) -> EventRequest {
+        let mut id = vec![0; 32];
+        id[usize::from(zeros / 8)] = 0x80 >> (zeros % 8);
+
+        EventRequest {
+            event: Some(nauthz_grpc::Event {
+                id,
+                pubkey: author.serialize().to_vec(),
+                created_at: 0,
+                kind,
+                content: String::new(),
+                tags: tags
+                    .iter()
+                    .map(|t| TagEntry {
+                        values: t.iter().map(|v| v.to_string()).collect(),
+                    })
+                    .collect(),
+                sig: vec![],
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn decide(authz: &EventAuthz, req: &EventRequest) -> (Decision, DecisionReason) {
+        let (reply, reason) = authz.decide(req).await.unwrap();
+
+        (reply.decision(), reason)
+    }
+
+    fn pow_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.pow.difficulty = Some(8);
+        settings.pow.kinds = vec![KindDifficulty {
+            kind: 4,
+            difficulty: 16,
+        }];
+
+        settings
+    }
+
+    #[tokio::test]
+    async fn test_pow_admits_unknown_pubkeys() {
+        let authz = authz(pow_settings());
+        let author = Keys::generate().public_key();
+
+        let req = request(author, 1, &[], 8);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Pow)
+        );
+
+        let req = request(author, 4, &[], 8);
+        let (reply, reason) = authz.decide(&req).await.unwrap();
+        assert_eq!(reply.decision(), Decision::Deny);
+        assert_eq!(reason, DecisionReason::Pow);
+        assert_eq!(reply.message.unwrap(), "pow: difficulty 16 required");
+    }
+
+    #[tokio::test]
+    async fn test_pow_does_not_override_status() {
+        let authz = authz(pow_settings());
+        let denied = Keys::generate().public_key();
+        authz.repo.lock().await.denied_pubkeys.insert(denied);
+
+        let req = request(denied, 1, &[], 32);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Denied)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pow_requires_committed_nonce() {
+        let mut settings = pow_settings();
+        settings.pow.require_nonce = true;
+        let authz = authz(settings);
+        let author = Keys::generate().public_key();
+
+        let req = request(author, 1, &[&["nonce", "1", "4"]], 12);
+        assert_eq!(decide(&authz, &req).await.0, Decision::Deny);
+
+        let req = request(author, 1, &[&["nonce", "1", "8"]], 12);
+        assert_eq!(decide(&authz, &req).await.0, Decision::Permit);
+    }
+
+    fn kind_rule(name: &str, kind: u64, policy: KindPolicy) -> KindRule {
+        KindRule {
+            name: name.to_string(),
+            kinds: vec![kind],
+            ranges: vec![],
+            classes: vec![],
+            policy,
+            message: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kind_rule_restricts_before_status_and_pow() {
+        let mut settings = pow_settings();
+        settings.info.implicit_allow = true;
+        let admin = Keys::generate().public_key();
+        settings.info.admins.insert(admin);
+        let authz = authz(settings);
+        let member = Keys::generate().public_key();
+        let unknown = Keys::generate().public_key();
+        {
+            let mut repo = authz.repo.lock().await;
+            repo.allowed_pubkeys.insert(member);
+            repo.set_kind_rules(vec![
+                kind_rule("admins", 30_000, KindPolicy::Admins),
+                kind_rule("members", 1, KindPolicy::Members),
+            ]);
+        }
+        let restricted = |name: &str| (Decision::Deny, DecisionReason::KindRule(name.to_string()));
+
+        let req = request(member, 30_000, &[], 32);
+        assert_eq!(decide(&authz, &req).await, restricted("admins"));
+        let req = request(admin, 30_000, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Admin)
+        );
+
+        // Neither implicit allow nor proof of work admit unknown pubkeys to a members kind
+        let req = request(unknown, 1, &[], 32);
+        assert_eq!(decide(&authz, &req).await, restricted("members"));
+        let req = request(unknown, 2, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::ImplicitAllow)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_public_kind_admits_unknown_but_not_denied() {
+        let authz = authz(Settings::default());
+        let unknown = Keys::generate().public_key();
+        let denied = Keys::generate().public_key();
+        {
+            let mut repo = authz.repo.lock().await;
+            repo.denied_pubkeys.insert(denied);
+            repo.set_kind_rules(vec![kind_rule("public", 7, KindPolicy::Anyone)]);
+        }
+
+        let req = request(unknown, 7, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (
+                Decision::Permit,
+                DecisionReason::KindRule("public".to_string())
+            )
+        );
+
+        let req = request(denied, 7, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Denied)
+        );
+
+        let req = request(unknown, 1, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Unknown)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inbound_admitted_before_pow_and_rate_limited() {
+        let mut settings = pow_settings();
+        settings.inbound.enabled = true;
+        settings.inbound.kinds = HashSet::from([4]);
+        settings.inbound.rate_limit = Some(1);
+        let authz = authz(settings);
+        let member = Keys::generate().public_key();
+        let sender = Keys::generate().public_key();
+        authz.repo.lock().await.allowed_pubkeys.insert(member);
+        let member_hex = member.to_string();
+
+        let req = request(sender, 4, &[&["p", &member_hex]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Inbound)
+        );
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::RateLimited)
+        );
 
-async fn get_users(
-    headers: HeaderMap,
-    State(state): State<AppState>,
-) -> Result<Json<Users>, (StatusCode, String)> {
-    debug!("{}", state.api_key);
-    if let Some(key) = headers.get("X-Api-Key") {
-        if key.eq(&state.api_key) {
-            let users = state.repo.lock().await.get_users();
-            return Ok(Json(users));
-        }
-        return Err((StatusCode::UNAUTHORIZED, "Invalid API Key".to_string()));
+        // Events not addressed to a member fall through to proof of work
+        let req = request(sender, 4, &[&["p", &sender.to_string()]], 16);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Pow)
+        );
     }
 
-    Err((StatusCode::UNAUTHORIZED, "No Api Key".to_string()))
+    #[tokio::test]
+    async fn test_self_deletion_of_own_coordinates() {
+        let mut settings = Settings::default();
+        settings.info.allow_self_deletion = true;
+        let authz = authz(settings);
+        let author = Keys::generate().public_key();
+        let denied = Keys::generate().public_key();
+        authz.repo.lock().await.denied_pubkeys.insert(denied);
+        let own = format!("30023:{author}:post");
+        let denied_own = format!("30023:{denied}:post");
+        let other = format!("30023:{}:post", Keys::generate().public_key());
+
+        let req = request(author, 5, &[&["a", &own]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::SelfDeletion)
+        );
+        let req = request(denied, 5, &[&["a", &denied_own]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::SelfDeletion)
+        );
+
+        let req = request(author, 5, &[&["a", &own], &["a", &other]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Unknown)
+        );
+        let req = request(author, 5, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Unknown)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_self_deletion_disabled() {
+        let authz = authz(Settings::default());
+        let author = Keys::generate().public_key();
+        let own = format!("30023:{author}:post");
+
+        let req = request(author, 5, &[&["a", &own]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Unknown)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_checked_before_status_except_for_admins() {
+        let mut settings = Settings::default();
+        let admin = Keys::generate().public_key();
+        settings.info.admins.insert(admin);
+        let authz = authz(settings);
+        let member = Keys::generate().public_key();
+        {
+            let mut repo = authz.repo.lock().await;
+            repo.allowed_pubkeys.insert(member);
+            repo.blocked_events.insert(format!("80{}", "00".repeat(31)));
+        }
+
+        let req = request(member, 1, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::Blocked)
+        );
+        let req = request(admin, 1, &[], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Admin)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denied_reference_checked_before_status() {
+        let mut settings = Settings::default();
+        settings.denied_references.enabled = true;
+        settings.denied_references.kinds = HashSet::from([1]);
+        let authz = authz(settings);
+        let member = Keys::generate().public_key();
+        let denied = Keys::generate().public_key();
+        {
+            let mut repo = authz.repo.lock().await;
+            repo.allowed_pubkeys.insert(member);
+            repo.denied_pubkeys.insert(denied);
+        }
+        let denied_hex = denied.to_string();
+
+        let req = request(member, 1, &[&["p", &denied_hex]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::DeniedReference)
+        );
+        let id = "ab".repeat(32);
+        let req = request(member, 1, &[&["e", &id, "", "reply", &denied_hex]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Deny, DecisionReason::DeniedReference)
+        );
+
+        // Reports about denied pubkeys are still admitted
+        let req = request(member, 1984, &[&["p", &denied_hex, "spam"]], 0);
+        assert_eq!(
+            decide(&authz, &req).await,
+            (Decision::Permit, DecisionReason::Allowed)
+        );
+    }
 }
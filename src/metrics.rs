@@ -0,0 +1,144 @@
+//! Prometheus metrics, labels are kept bounded so no label holds a pubkey or event id
+
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::kinds::KindClass;
+use crate::nauthz_grpc::Decision;
+use crate::DecisionReason;
+
+pub struct Metrics {
+    registry: Registry,
+    decisions: IntCounterVec,
+    decision_duration: Histogram,
+    lock_wait: Histogram,
+    users: IntGaugeVec,
+    relay_publish: IntCounterVec,
+    relay_restore: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("nauthz".to_string()), None)?;
+
+        let decisions = IntCounterVec::new(
+            Opts::new(
+                "event_admit_decisions_total",
+                "Events decided by outcome, reason and NIP-01 kind class",
+            ),
+            &["decision", "reason", "kind_class"],
+        )?;
+        let decision_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "event_admit_duration_seconds",
+                "Time taken to decide an event",
+            )
+            .buckets(exponential_buckets(0.0001, 4.0, 10)?),
+        )?;
+        let lock_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "repo_lock_wait_seconds",
+                "Time spent waiting for the repo lock while deciding events",
+            )
+            .buckets(exponential_buckets(0.00001, 4.0, 10)?),
+        )?;
+        let users = IntGaugeVec::new(
+            Opts::new("users", "Pubkeys on the allow and deny lists"),
+            &["status"],
+        )?;
+        let relay_publish = IntCounterVec::new(
+            Opts::new(
+                "relay_publish_total",
+                "Lists and notices published to the relays by result",
+            ),
+            &["result"],
+        )?;
+        let relay_restore = IntCounterVec::new(
+            Opts::new(
+                "relay_restore_total",
+                "Restores of the lists from the relays by result",
+            ),
+            &["result"],
+        )?;
+
+        registry.register(Box::new(decisions.clone()))?;
+        registry.register(Box::new(decision_duration.clone()))?;
+        registry.register(Box::new(lock_wait.clone()))?;
+        registry.register(Box::new(users.clone()))?;
+        registry.register(Box::new(relay_publish.clone()))?;
+        registry.register(Box::new(relay_restore.clone()))?;
+
+        Ok(Self {
+            registry,
+            decisions,
+            decision_duration,
+            lock_wait,
+            users,
+            relay_publish,
+            relay_restore,
+        })
+    }
+
+    /// Count a decision, `None` when the event could not be decided
+    pub fn observe_decision(
+        &self,
+        decision: Option<(Decision, &DecisionReason)>,
+        kind: Option<u64>,
+        elapsed: Duration,
+    ) {
+        let (decision, reason) = match decision {
+            Some((Decision::Permit, reason)) => ("permit", reason.label()),
+            Some((_, reason)) => ("deny", reason.label()),
+            None => ("error", "error"),
+        };
+        let kind_class = kind.map(|k| KindClass::of(k).label()).unwrap_or("none");
+
+        self.decisions
+            .with_label_values(&[decision, reason, kind_class])
+            .inc();
+        self.decision_duration.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_lock_wait(&self, elapsed: Duration) {
+        self.lock_wait.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_users(&self, allowed: usize, denied: usize) {
+        self.users
+            .with_label_values(&["allowed"])
+            .set(allowed as i64);
+        self.users.with_label_values(&["denied"]).set(denied as i64);
+    }
+
+    pub fn record_publish<T, E>(&self, result: &Result<T, E>) {
+        self.relay_publish
+            .with_label_values(&[result_label(result)])
+            .inc();
+    }
+
+    pub fn record_restore<T, E>(&self, result: &Result<T, E>) {
+        self.relay_restore
+            .with_label_values(&[result_label(result)])
+            .inc();
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+fn result_label<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
@@ -0,0 +1,79 @@
+//! Publishing of signed events to the configured relays
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use ::url::Url;
+use anyhow::Result;
+use nostr_sdk::client::Client;
+use nostr_sdk::event::Event;
+use nostr_sdk::key::{Keys, XOnlyPublicKey};
+use nostr_sdk::EventBuilder;
+
+use crate::health::Health;
+use crate::metrics::Metrics;
+
+/// Shared outside of the repo lock so changes are published without holding it
+pub struct Publisher {
+    key: Keys,
+    relays: HashSet<Url>,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+}
+
+impl Publisher {
+    pub fn new(
+        key: Keys,
+        relays: HashSet<Url>,
+        health: Arc<Health>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            key,
+            relays,
+            health,
+            metrics,
+        }
+    }
+
+    pub async fn publish(&self, event: Event) -> Result<()> {
+        let relays = self.relays.iter().map(|x| (x.to_string(), None)).collect();
+
+        let client = Client::new(&self.key);
+        client.add_relays(relays).await?;
+        client.connect().await;
+
+        let sent = client.send_event(event).await;
+        self.health.record_sync(&client).await;
+        self.metrics.record_publish(&sent);
+        client.shutdown().await?;
+        sent?;
+
+        Ok(())
+    }
+
+    /// Publish the events in order, stopping at the first that could not be sent
+    pub async fn publish_all(&self, events: Vec<Event>) -> Result<()> {
+        for event in events {
+            self.publish(event).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send an encrypted direct message to each of the `admins`
+    pub async fn notify_admins(
+        &self,
+        admins: &HashSet<XOnlyPublicKey>,
+        message: &str,
+    ) -> Result<()> {
+        for admin in admins {
+            let event = EventBuilder::new_encrypted_direct_msg(&self.key, *admin, message, None)?
+                .to_event(&self.key)?;
+
+            self.publish(event).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,18 +1,107 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use ::url::Url;
 use anyhow::Result;
 use nostr_sdk::client::Client;
 use nostr_sdk::key::{Keys, XOnlyPublicKey};
 use nostr_sdk::prelude::*;
 use nostr_sdk::prelude::{decrypt, encrypt};
 use nostr_sdk::{EventBuilder, Tag};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use url::Url;
+use tokio::sync::broadcast;
 
+use crate::api::{Blocklist, Users};
+use crate::auth::{ApiKey, Scope};
+use crate::config::Reports;
+use crate::content::{ContentFilter, ContentRule};
+use crate::health::Health;
+use crate::kinds::{KindRule, KindRules};
+use crate::lookup::EventAuthors;
+use crate::metrics::Metrics;
 use crate::nauthz_grpc::event::TagEntry;
 use crate::nauthz_grpc::Event;
-use crate::{UserStatus, Users};
+use crate::publisher::Publisher;
+use crate::utils::{self, unix_time};
+use crate::UserStatus;
+
+const DEFAULT_CHANGE_LOG_SIZE: usize = 10_000;
+
+/// Identifier of the application data holding the invites of each member
+const INVITES: &str = "invites";
+/// Identifier of the application data holding the vouches for each candidate
+const VOUCHES: &str = "vouches";
+const CONTENT_RULES: &str = "content_rules";
+const KIND_RULES: &str = "kind_rules";
+const BLOCKLIST: &str = "blocklist";
+/// Identifier of the application data holding the record of each pubkey
+const RECORDS: &str = "records";
+/// Identifier of the application data holding the api keys created at runtime
+const API_KEYS: &str = "api_keys";
+
+/// Latest of the application data `events` published under the `identifier`
+fn latest_data<'a>(
+    events: &'a [nostr_sdk::event::Event],
+    identifier: &str,
+) -> Option<&'a nostr_sdk::event::Event> {
+    events
+        .iter()
+        .filter(|e| {
+            e.tags.iter().any(|t| match t {
+                Tag::Identifier(d) => d.eq(identifier),
+                _ => false,
+            })
+        })
+        .max_by_key(|e| e.created_at)
+}
+
+/// Api key as persisted, with the hash left out when keys are listed
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    id: String,
+    hash: String,
+    scope: Scope,
+    expires_at: Option<u64>,
+}
+
+/// Who changed the status of a pubkey
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Actor {
+    /// Http api key with its id
+    ApiKey(String),
+    /// Pubkey such as the relay key publishing a list or an inviting member
+    Pubkey(XOnlyPublicKey),
+    /// Automatic change such as vouches, reports or expiring denials
+    System(String),
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ApiKey(id) => write!(f, "api_key:{id}"),
+            Self::Pubkey(pubkey) => write!(f, "pubkey:{pubkey}"),
+            Self::System(name) => write!(f, "system:{name}"),
+        }
+    }
+}
+
+/// When and by whom the status of a pubkey was last changed, with its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// Unix time the pubkey was first added to a list
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub updated_by: Actor,
+    /// Reason for the status of the pubkey
+    pub note: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeSet<String>,
+}
 
 #[derive(Clone)]
 pub struct Repo {
@@ -20,30 +109,181 @@ pub struct Repo {
     pub relays: HashSet<Url>,
     pub allowed_pubkeys: HashSet<XOnlyPublicKey>,
     pub denied_pubkeys: HashSet<XOnlyPublicKey>,
+    /// Pubkeys invited by each member
+    pub invites: HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>>,
+    /// Members that have vouched for each candidate
+    pub vouches: HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>>,
+    /// Unix time at which the denial of a pubkey expires
+    pub deny_expiry: HashMap<XOnlyPublicKey, u64>,
+    /// Members denied for a limited time, allowed again when their denial expires
+    pub suspended_members: HashSet<XOnlyPublicKey>,
+    /// Reporters of each pubkey by report type
+    pub reports: HashMap<XOnlyPublicKey, HashMap<String, HashSet<XOnlyPublicKey>>>,
+    pub content_filter: ContentFilter,
+    pub kind_rules: KindRules,
+    /// Start of the current rate limit window and the number of inbound events admitted in it per sender
+    pub inbound_senders: HashMap<XOnlyPublicKey, (u64, u32)>,
+    /// Hex ids of blocked events
+    pub blocked_events: HashSet<String>,
+    /// Normalized content hashes of blocked events
+    pub blocked_hashes: HashSet<String>,
+    /// Last status change of each pubkey
+    pub records: HashMap<XOnlyPublicKey, UserRecord>,
+    /// Pubkeys on either list ordered by the time they were added, `created_at` of their record
+    pub added_index: BTreeSet<(u64, XOnlyPublicKey)>,
+    /// Log of the last `change_log_size` changes to the lists, kept in memory only
+    pub changes: VecDeque<Change>,
+    pub change_log_size: usize,
+    /// Id of the next logged change, ids keep increasing as old changes are dropped
+    next_change_id: u64,
+    /// Changes as they are logged
+    pub change_sender: broadcast::Sender<Change>,
+    /// Http api keys by id
+    pub api_keys: HashMap<String, ApiKey>,
+    /// Readiness and relay status, read without the repo lock
+    pub health: Arc<Health>,
+    pub metrics: Arc<Metrics>,
+    /// Authors of referenced events, looked up without the repo lock
+    pub event_authors: Arc<EventAuthors>,
+    /// Publishes the changes returned by the admission methods without the repo lock
+    pub publisher: Arc<Publisher>,
+}
+
+/// Channel through which a change to the lists was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeSource {
+    Http,
+    /// Categorized People List published with the relay key
+    NostrList,
+    Invite,
+    Vouch,
+    Report,
+    Expiry,
+    Revert,
+    /// NIP-86 relay management api
+    Nip86,
+    /// gRPC admin service
+    Grpc,
+}
+
+impl fmt::Display for ChangeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self {
+            Self::Http => "http",
+            Self::NostrList => "nostr_list",
+            Self::Invite => "invite",
+            Self::Vouch => "vouch",
+            Self::Report => "report",
+            Self::Expiry => "expiry",
+            Self::Revert => "revert",
+            Self::Nip86 => "nip86",
+            Self::Grpc => "grpc",
+        };
+
+        write!(f, "{source}")
+    }
+}
+
+/// Pubkeys added to and removed from a list
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListDiff {
+    pub added: HashSet<XOnlyPublicKey>,
+    pub removed: HashSet<XOnlyPublicKey>,
+}
+
+impl ListDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Entry of the change log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub id: u64,
+    pub created_at: u64,
+    pub actor: Actor,
+    pub source: ChangeSource,
+    pub allow: ListDiff,
+    pub deny: ListDiff,
+}
+
+impl Change {
+    fn pubkeys(&self) -> HashSet<XOnlyPublicKey> {
+        [&self.allow, &self.deny]
+            .iter()
+            .flat_map(|d| d.added.iter().chain(d.removed.iter()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Whether each pubkey was allowed and denied before a change
+type Membership = Vec<(XOnlyPublicKey, bool, bool)>;
+
+/// Position in the ordered user index after which a page starts
+pub type UserCursor = (u64, XOnlyPublicKey);
+
+/// Allowed or denied pubkey with the time it was added to its list
+#[derive(Debug, Clone)]
+pub struct UserEntry {
+    pub pubkey: XOnlyPublicKey,
+    pub status: UserStatus,
+    pub added_at: u64,
 }
 
 impl Repo {
     pub fn new(key: Keys, relays: HashSet<Url>) -> Result<Self> {
+        let health = Arc::new(Health::new(&relays));
+        let metrics = Arc::new(Metrics::new()?);
+
         Ok(Repo {
+            publisher: Arc::new(Publisher::new(
+                key.clone(),
+                relays.clone(),
+                health.clone(),
+                metrics.clone(),
+            )),
+            health,
+            metrics,
+            event_authors: Arc::new(EventAuthors::new(key.clone(), relays.clone())),
             key,
             relays,
             allowed_pubkeys: HashSet::new(),
             denied_pubkeys: HashSet::new(),
+            invites: HashMap::new(),
+            vouches: HashMap::new(),
+            deny_expiry: HashMap::new(),
+            suspended_members: HashSet::new(),
+            reports: HashMap::new(),
+            content_filter: ContentFilter::default(),
+            kind_rules: KindRules::default(),
+            inbound_senders: HashMap::new(),
+            blocked_events: HashSet::new(),
+            blocked_hashes: HashSet::new(),
+            records: HashMap::new(),
+            added_index: BTreeSet::new(),
+            changes: VecDeque::new(),
+            change_log_size: DEFAULT_CHANGE_LOG_SIZE,
+            next_change_id: 1,
+            change_sender: broadcast::channel(1024).0,
+            api_keys: HashMap::new(),
         })
     }
 
     pub async fn publish_event(&self, event: nostr_sdk::event::Event) -> Result<()> {
-        let relays = self.relays.iter().map(|x| (x.to_string(), None)).collect();
-
-        let client = Client::new(&self.key);
-        client.add_relays(relays).await?;
+        self.publisher.publish(event).await
+    }
 
-        client.send_event(event).await?;
+    pub async fn restore_user_list(&mut self) -> Result<()> {
+        let restored = self.fetch_user_list().await;
+        self.metrics.record_restore(&restored);
 
-        Ok(())
+        restored
     }
 
-    pub async fn restore_user_list(&mut self) -> Result<()> {
+    async fn fetch_user_list(&mut self) -> Result<()> {
         let relays = self.relays.iter().map(|x| (x.to_string(), None)).collect();
 
         let client = Client::new(&self.key);
@@ -60,8 +300,10 @@ impl Repo {
             .get_events_of(vec![subscription], Some(timeout))
             .await?;
 
+        let mut allowed_at = 0;
         if let Some(allow_event) = allow_events.iter().max_by_key(|e| e.created_at) {
             self.allowed_pubkeys = self.pubkeys_from_nostr(allow_event.to_owned())?;
+            allowed_at = allow_event.created_at.as_u64();
         }
 
         let subscription = Filter::new()
@@ -74,54 +316,370 @@ impl Repo {
             .get_events_of(vec![subscription], Some(timeout))
             .await?;
 
+        let mut denied_at = 0;
         if let Some(deny_event) = deny_events.iter().max_by_key(|e| e.created_at) {
             self.denied_pubkeys = self.pubkeys_from_nostr(deny_event.clone())?;
+            (self.deny_expiry, self.suspended_members) = self.deny_expiry_from_nostr(deny_event);
+            denied_at = deny_event.created_at.as_u64();
+        }
+
+        let subscription = Filter::new()
+            .authors(vec![self.key.public_key().to_string()])
+            .identifiers(vec![
+                INVITES,
+                VOUCHES,
+                CONTENT_RULES,
+                KIND_RULES,
+                BLOCKLIST,
+                RECORDS,
+                API_KEYS,
+            ])
+            .kind(Kind::ApplicationSpecificData);
+
+        let timeout = Duration::from_secs(10);
+        let data_events = client
+            .get_events_of(vec![subscription], Some(timeout))
+            .await?;
+        self.health.record_sync(&client).await;
+        client.shutdown().await?;
+
+        if let Some(event) = latest_data(&data_events, INVITES) {
+            self.invites = self.data_from_nostr(event)?;
+        }
+        if let Some(event) = latest_data(&data_events, VOUCHES) {
+            self.vouches = self.data_from_nostr(event)?;
+        }
+        if let Some(event) = latest_data(&data_events, CONTENT_RULES) {
+            self.set_content_rules(self.data_from_nostr(event)?)?;
+        }
+        if let Some(event) = latest_data(&data_events, KIND_RULES) {
+            self.set_kind_rules(self.data_from_nostr(event)?);
+        }
+        if let Some(event) = latest_data(&data_events, BLOCKLIST) {
+            let blocklist: Blocklist = self.data_from_nostr(event)?;
+            self.blocked_events = blocklist.events;
+            self.blocked_hashes = blocklist.hashes;
+        }
+        let records = match latest_data(&data_events, RECORDS) {
+            Some(event) => self.data_from_nostr(event)?,
+            None => HashMap::new(),
+        };
+        self.restore_records(records, allowed_at, denied_at);
+        if let Some(event) = latest_data(&data_events, API_KEYS) {
+            let keys: Vec<StoredApiKey> = self.data_from_nostr(event)?;
+            self.restore_api_keys(keys);
         }
+
         Ok(())
     }
 
-    pub async fn admit_pubkeys(&mut self, pubkeys: &HashSet<XOnlyPublicKey>) -> Result<()> {
-        self.allowed_pubkeys.extend(pubkeys);
-        self.denied_pubkeys
-            .retain(|p| !self.allowed_pubkeys.contains(p));
+    /// Replace the records with the restored `records` and rebuild the index from them
+    ///
+    /// Listed pubkeys without a record, such as those added before records were published,
+    /// are recorded at the time of the list they are on.
+    fn restore_records(
+        &mut self,
+        records: HashMap<XOnlyPublicKey, UserRecord>,
+        allowed_at: u64,
+        denied_at: u64,
+    ) {
+        self.records = records;
+        self.added_index = self
+            .records
+            .iter()
+            .filter(|(p, _)| self.allowed_pubkeys.contains(p) || self.denied_pubkeys.contains(p))
+            .map(|(p, r)| (r.created_at, *p))
+            .collect();
 
-        let allowed: Vec<_> = pubkeys
+        let actor = Actor::Pubkey(self.key.public_key());
+        let allowed: HashSet<XOnlyPublicKey> = self
+            .allowed_pubkeys
             .iter()
-            .map(|p| Tag::PubKey(p.to_owned(), None))
+            .filter(|p| !self.records.contains_key(p))
+            .cloned()
+            .collect();
+        self.record_at(&allowed, &actor, allowed_at);
+        let denied: HashSet<XOnlyPublicKey> = self
+            .denied_pubkeys
+            .iter()
+            .filter(|p| !self.records.contains_key(p))
+            .cloned()
             .collect();
-        let json_string = serde_json::to_string(&allowed)?;
+        self.record_at(&denied, &actor, denied_at);
+    }
+
+    /// Publish `data` as encrypted NIP-78 application data under the `identifier`
+    async fn publish_data<T: Serialize>(&self, identifier: &str, data: &T) -> Result<()> {
+        self.publish_event(self.data_event(identifier, data)?).await
+    }
 
+    /// Sign `data` as encrypted NIP-78 application data under the `identifier`
+    fn data_event<T: Serialize>(
+        &self,
+        identifier: &str,
+        data: &T,
+    ) -> Result<nostr_sdk::event::Event> {
         let encrypted = encrypt(
-            &self.key.secret_key().unwrap(),
+            &self.key.secret_key()?,
             &self.key.public_key(),
-            json_string,
+            serde_json::to_string(data)?,
         )?;
 
         let event = EventBuilder::new(
-            nostr_sdk::Kind::CategorizedPeopleList,
+            Kind::ApplicationSpecificData,
             encrypted,
             &[Tag::Generic(
                 nostr_sdk::TagKind::D,
-                vec!["allow".to_string()],
+                vec![identifier.to_string()],
             )],
         )
         .to_event(&self.key)?;
 
-        self.publish_event(event).await?;
+        Ok(event)
+    }
 
-        Ok(())
+    fn data_from_nostr<T: DeserializeOwned>(&self, event: &nostr_sdk::event::Event) -> Result<T> {
+        let content = decrypt(
+            &self.key.secret_key()?,
+            &self.key.public_key(),
+            &event.content,
+        )?;
+
+        Ok(serde_json::from_str(&content)?)
     }
 
-    pub async fn deny_pubkeys(&mut self, pubkeys: &HashSet<XOnlyPublicKey>) -> Result<()> {
-        self.denied_pubkeys.extend(pubkeys);
+    /// Record that `actor` changed the status of the pubkeys at `updated_at`
+    ///
+    /// Listed pubkeys missing from the index were just added to a list and are indexed at `updated_at`.
+    fn record_at(&mut self, pubkeys: &HashSet<XOnlyPublicKey>, actor: &Actor, updated_at: u64) {
+        for pubkey in pubkeys {
+            let listed =
+                self.allowed_pubkeys.contains(pubkey) || self.denied_pubkeys.contains(pubkey);
+            let record = self.records.entry(*pubkey).or_insert(UserRecord {
+                created_at: updated_at,
+                updated_at,
+                updated_by: actor.clone(),
+                note: None,
+                labels: BTreeSet::new(),
+            });
+            if listed && !self.added_index.contains(&(record.created_at, *pubkey)) {
+                record.created_at = updated_at;
+                self.added_index.insert((updated_at, *pubkey));
+            }
+            record.updated_at = updated_at;
+            record.updated_by = actor.clone();
+        }
+    }
+
+    /// Drop the pubkeys that are on neither list from the index
+    fn unindex(&mut self, pubkeys: &HashSet<XOnlyPublicKey>) {
+        for pubkey in pubkeys {
+            if self.allowed_pubkeys.contains(pubkey) || self.denied_pubkeys.contains(pubkey) {
+                continue;
+            }
+            if let Some(record) = self.records.get(pubkey) {
+                self.added_index.remove(&(record.created_at, *pubkey));
+            }
+        }
+    }
+
+    fn record(&mut self, pubkeys: &HashSet<XOnlyPublicKey>, actor: &Actor) {
+        self.record_at(pubkeys, actor, unix_time());
+    }
+
+    /// Attach a note to the pubkeys, replacing their previous note, an empty note clears it
+    pub fn set_note(&mut self, pubkeys: &HashSet<XOnlyPublicKey>, note: &str) {
+        for pubkey in pubkeys {
+            if let Some(record) = self.records.get_mut(pubkey) {
+                record.note = Some(note.to_string()).filter(|n| !n.is_empty());
+            }
+        }
+    }
+
+    /// Replace the labels of the pubkeys
+    pub fn set_labels(&mut self, pubkeys: &HashSet<XOnlyPublicKey>, labels: &BTreeSet<String>) {
+        for pubkey in pubkeys {
+            if let Some(record) = self.records.get_mut(pubkey) {
+                record.labels = labels.clone();
+            }
+        }
+    }
+
+    /// Publish the records, to be called after changing notes or labels
+    pub async fn save_records(&self) -> Result<()> {
+        self.publish_data(RECORDS, &self.records).await
+    }
+
+    pub fn get_record(&self, pubkey: &XOnlyPublicKey) -> Option<UserRecord> {
+        self.records.get(pubkey).cloned()
+    }
+
+    /// Unix time at which the denial of the pubkey expires, if it is time limited
+    pub fn get_deny_expiry(&self, pubkey: &XOnlyPublicKey) -> Option<u64> {
+        self.deny_expiry.get(pubkey).copied()
+    }
+
+    fn membership(&self, pubkeys: &HashSet<XOnlyPublicKey>) -> Membership {
+        pubkeys
+            .iter()
+            .map(|p| {
+                (
+                    *p,
+                    self.allowed_pubkeys.contains(p),
+                    self.denied_pubkeys.contains(p),
+                )
+            })
+            .collect()
+    }
+
+    /// Append the difference between `before` and the current lists to the change log
+    fn log_change(
+        &mut self,
+        before: Membership,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Option<Change> {
+        let mut allow = ListDiff::default();
+        let mut deny = ListDiff::default();
+
+        for (pubkey, allowed, denied) in before {
+            match (allowed, self.allowed_pubkeys.contains(&pubkey)) {
+                (false, true) => allow.added.insert(pubkey),
+                (true, false) => allow.removed.insert(pubkey),
+                _ => false,
+            };
+            match (denied, self.denied_pubkeys.contains(&pubkey)) {
+                (false, true) => deny.added.insert(pubkey),
+                (true, false) => deny.removed.insert(pubkey),
+                _ => false,
+            };
+        }
+
+        if allow.is_empty() && deny.is_empty() {
+            return None;
+        }
+
+        let change = Change {
+            id: self.next_change_id,
+            created_at: unix_time(),
+            actor: actor.clone(),
+            source,
+            allow,
+            deny,
+        };
+        self.next_change_id += 1;
+        if self.changes.len() >= self.change_log_size {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change.clone());
+        // No receivers is not an error
+        let _ = self.change_sender.send(change.clone());
+
+        Some(change)
+    }
+
+    /// Logged changes after `after_id` and a receiver of the changes logged from now on
+    pub fn subscribe_changes(&self, after_id: u64) -> (Vec<Change>, broadcast::Receiver<Change>) {
+        let logged = self
+            .changes
+            .iter()
+            .filter(|c| c.id > after_id)
+            .cloned()
+            .collect();
+
+        (logged, self.change_sender.subscribe())
+    }
+
+    /// Changes made at or after `since`
+    pub fn get_changes(&self, since: u64) -> Vec<Change> {
+        self.changes
+            .iter()
+            .filter(|c| c.created_at >= since)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_change(&self, id: u64) -> Option<Change> {
+        self.changes.iter().find(|c| c.id.eq(&id)).cloned()
+    }
+
+    /// Apply the inverse of change `id` and republish both lists
+    ///
+    /// Returns the change made by the revert, if it changed anything.
+    pub async fn revert_change(&mut self, id: u64, actor: &Actor) -> Result<Option<Change>> {
+        let change = self
+            .get_change(id)
+            .ok_or(anyhow::anyhow!("Unknown change {id}"))?;
+
+        let revert = self.apply_revert(&change, actor);
+
+        self.publish_list("allow", &self.allowed_pubkeys).await?;
+        self.publish_list("deny", &self.denied_pubkeys).await?;
+        self.save_records().await?;
+
+        Ok(revert)
+    }
+
+    /// Apply the inverse of `change` to the lists
+    ///
+    /// Pubkeys put back on one list are taken off the other, as they may have been added to it since the change.
+    fn apply_revert(&mut self, change: &Change, actor: &Actor) -> Option<Change> {
+        let pubkeys = change.pubkeys();
+        let before = self.membership(&pubkeys);
+
         self.allowed_pubkeys
-            .retain(|p| !self.denied_pubkeys.contains(p));
+            .retain(|p| !change.allow.added.contains(p) && !change.deny.removed.contains(p));
+        self.allowed_pubkeys.extend(&change.allow.removed);
+        self.denied_pubkeys
+            .retain(|p| !change.deny.added.contains(p) && !change.allow.removed.contains(p));
+        self.deny_expiry
+            .retain(|p, _| !change.deny.added.contains(p) && !change.allow.removed.contains(p));
+        self.denied_pubkeys.extend(&change.deny.removed);
+        self.suspended_members
+            .retain(|p| self.deny_expiry.contains_key(p));
+        self.reports.retain(|p, _| !pubkeys.contains(p));
+        self.record(&pubkeys, actor);
+        self.unindex(&pubkeys);
+
+        self.log_change(before, actor, ChangeSource::Revert)
+    }
 
-        let denied: Vec<_> = pubkeys
+    /// Publish the full `allow` or `deny` list as an encrypted Categorized People List
+    async fn publish_list(
+        &self,
+        identifier: &str,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+    ) -> Result<()> {
+        self.publish_event(self.list_event(identifier, pubkeys)?)
+            .await
+    }
+
+    /// Sign the full `allow` or `deny` list as an encrypted Categorized People List
+    ///
+    /// Time limited denials are saved as `["expiration", <unix time>, <pubkey>]` entries of the list,
+    /// with a trailing `"allow"` for members put back on the allow list when the denial expires.
+    fn list_event(
+        &self,
+        identifier: &str,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+    ) -> Result<nostr_sdk::event::Event> {
+        let mut tags: Vec<_> = pubkeys
             .iter()
             .map(|p| Tag::PubKey(p.to_owned(), None))
             .collect();
-        let json_string = serde_json::to_string(&denied)?;
+        tags.extend(
+            self.deny_expiry
+                .iter()
+                .filter(|(p, _)| identifier.eq("deny") && pubkeys.contains(p))
+                .map(|(p, expires_at)| {
+                    let mut values = vec![expires_at.to_string(), p.to_string()];
+                    if self.suspended_members.contains(p) {
+                        values.push("allow".to_string());
+                    }
+                    Tag::Generic(nostr_sdk::TagKind::Expiration, values)
+                }),
+        );
+        let json_string = serde_json::to_string(&tags)?;
 
         let encrypted = encrypt(
             &self.key.secret_key().unwrap(),
@@ -134,16 +692,571 @@ impl Repo {
             encrypted,
             &[Tag::Generic(
                 nostr_sdk::TagKind::D,
-                vec!["deny".to_string()],
+                vec![identifier.to_string()],
             )],
         )
         .to_event(&self.key)?;
 
-        self.publish_event(event).await?;
+        Ok(event)
+    }
+
+    pub async fn admit_pubkeys(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<()> {
+        let events = self.admit(pubkeys, actor, source)?;
+
+        self.publisher.publish_all(events).await
+    }
+
+    /// Add pubkeys to the allow list, returns the lists to publish
+    fn admit(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<Vec<nostr_sdk::event::Event>> {
+        let before = self.membership(pubkeys);
+        self.allowed_pubkeys.extend(pubkeys);
+        self.record(pubkeys, actor);
+        let denied_count = self.denied_pubkeys.len();
+        self.denied_pubkeys
+            .retain(|p| !self.allowed_pubkeys.contains(p));
+        self.deny_expiry.retain(|p, _| !pubkeys.contains(p));
+        self.suspended_members.retain(|p| !pubkeys.contains(p));
+        self.reports.retain(|p, _| !pubkeys.contains(p));
+        self.log_change(before, actor, source);
+
+        let mut events = vec![self.list_event("allow", &self.allowed_pubkeys)?];
+        if self.denied_pubkeys.len() != denied_count {
+            events.push(self.list_event("deny", &self.denied_pubkeys)?);
+        }
+        events.push(self.data_event(RECORDS, &self.records)?);
+
+        Ok(events)
+    }
+
+    pub async fn deny_pubkeys(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<()> {
+        let events = self.deny(pubkeys, None, actor, source)?;
+
+        self.publisher.publish_all(events).await
+    }
+
+    /// Add pubkeys to the deny list, returns the lists to publish
+    fn deny(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        expires_at: Option<u64>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<Vec<nostr_sdk::event::Event>> {
+        let before = self.membership(pubkeys);
+        let members: HashSet<XOnlyPublicKey> = pubkeys
+            .iter()
+            .filter(|p| self.allowed_pubkeys.contains(p) || self.suspended_members.contains(p))
+            .cloned()
+            .collect();
+        self.denied_pubkeys.extend(pubkeys);
+        self.record(pubkeys, actor);
+        let allowed_count = self.allowed_pubkeys.len();
+        self.allowed_pubkeys
+            .retain(|p| !self.denied_pubkeys.contains(p));
+        self.deny_expiry.retain(|p, _| !pubkeys.contains(p));
+        self.suspended_members.retain(|p| !pubkeys.contains(p));
+        if let Some(expires_at) = expires_at {
+            self.deny_expiry
+                .extend(pubkeys.iter().map(|p| (*p, expires_at)));
+            self.suspended_members.extend(members);
+        }
+        self.reports.retain(|p, _| !pubkeys.contains(p));
+        self.log_change(before, actor, source);
+
+        let mut events = vec![self.list_event("deny", &self.denied_pubkeys)?];
+        if self.allowed_pubkeys.len() != allowed_count {
+            events.push(self.list_event("allow", &self.allowed_pubkeys)?);
+        }
+        events.push(self.data_event(RECORDS, &self.records)?);
+
+        Ok(events)
+    }
+
+    /// Remove pubkeys from both the allow and deny lists, making them unknown
+    pub async fn remove_pubkeys(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<()> {
+        let events = self.remove(pubkeys, actor, source)?;
+
+        self.publisher.publish_all(events).await
+    }
+
+    /// Take pubkeys off both lists, returns the lists to publish
+    fn remove(
+        &mut self,
+        pubkeys: &HashSet<XOnlyPublicKey>,
+        actor: &Actor,
+        source: ChangeSource,
+    ) -> Result<Vec<nostr_sdk::event::Event>> {
+        let before = self.membership(pubkeys);
+        self.record(pubkeys, actor);
+        self.allowed_pubkeys.retain(|p| !pubkeys.contains(p));
+        self.denied_pubkeys.retain(|p| !pubkeys.contains(p));
+        self.deny_expiry.retain(|p, _| !pubkeys.contains(p));
+        self.suspended_members.retain(|p| !pubkeys.contains(p));
+        self.reports.retain(|p, _| !pubkeys.contains(p));
+        self.unindex(pubkeys);
+        self.log_change(before, actor, source);
+
+        Ok(vec![
+            self.list_event("allow", &self.allowed_pubkeys)?,
+            self.list_event("deny", &self.denied_pubkeys)?,
+            self.data_event(RECORDS, &self.records)?,
+        ])
+    }
+
+    /// Remove the pubkeys whose denial has expired from the deny list
+    ///
+    /// Suspended members are put back on the allow list. Returns the pubkeys whose denial expired with
+    /// the lists to publish.
+    pub fn expire_denials(
+        &mut self,
+        now: u64,
+    ) -> Result<(HashSet<XOnlyPublicKey>, Vec<nostr_sdk::event::Event>)> {
+        let expired: HashSet<XOnlyPublicKey> = self
+            .deny_expiry
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(p, _)| *p)
+            .collect();
+
+        if expired.is_empty() {
+            return Ok((expired, Vec::new()));
+        }
+
+        let before = self.membership(&expired);
+        let actor = Actor::System("expiry".to_string());
+        let members: HashSet<XOnlyPublicKey> = self
+            .suspended_members
+            .intersection(&expired)
+            .cloned()
+            .collect();
+        self.deny_expiry.retain(|p, _| !expired.contains(p));
+        self.suspended_members.retain(|p| !expired.contains(p));
+        self.denied_pubkeys.retain(|p| !expired.contains(p));
+        self.allowed_pubkeys.extend(&members);
+        self.record(&expired, &actor);
+        self.unindex(&expired);
+        self.log_change(before, &actor, ChangeSource::Expiry);
+
+        let mut events = vec![self.list_event("deny", &self.denied_pubkeys)?];
+        if !members.is_empty() {
+            events.push(self.list_event("allow", &self.allowed_pubkeys)?);
+        }
+        events.push(self.data_event(RECORDS, &self.records)?);
+
+        Ok((expired, events))
+    }
+
+    /// Tally the reports of `reporter` and deny the pubkeys whose reports of a type reach its threshold
+    ///
+    /// Returns the denied pubkeys with the report type that crossed the threshold and the lists to publish.
+    pub fn report_pubkeys(
+        &mut self,
+        reporter: XOnlyPublicKey,
+        reports: &HashMap<XOnlyPublicKey, String>,
+        settings: &Reports,
+        exempt: &HashSet<XOnlyPublicKey>,
+    ) -> Result<(
+        HashMap<XOnlyPublicKey, String>,
+        Vec<nostr_sdk::event::Event>,
+    )> {
+        let banned = self.tally_reports(reporter, reports, settings, exempt);
+        if banned.is_empty() {
+            return Ok((banned, Vec::new()));
+        }
+
+        let pubkeys = banned.keys().cloned().collect();
+        let expires_at = settings.ban_duration.map(|duration| unix_time() + duration);
+        let events = self.deny(
+            &pubkeys,
+            expires_at,
+            &Actor::System("reports".to_string()),
+            ChangeSource::Report,
+        )?;
+
+        Ok((banned, events))
+    }
+
+    /// Count the reports of `reporter`, returns the pubkeys whose reports of a type reached its threshold
+    ///
+    /// Each reporter is counted once per reported pubkey and report type. Self reports, exempt and
+    /// already denied pubkeys are never returned.
+    fn tally_reports(
+        &mut self,
+        reporter: XOnlyPublicKey,
+        reports: &HashMap<XOnlyPublicKey, String>,
+        settings: &Reports,
+        exempt: &HashSet<XOnlyPublicKey>,
+    ) -> HashMap<XOnlyPublicKey, String> {
+        let mut banned = HashMap::new();
+
+        for (pubkey, report_type) in reports {
+            if pubkey.eq(&reporter) || exempt.contains(pubkey) {
+                continue;
+            }
+
+            let reporters = self
+                .reports
+                .entry(*pubkey)
+                .or_default()
+                .entry(report_type.clone())
+                .or_default();
+            reporters.insert(reporter);
+
+            let threshold = settings
+                .thresholds
+                .get(report_type)
+                .copied()
+                .unwrap_or(settings.threshold);
+
+            if threshold > 0
+                && reporters.len() >= threshold
+                && !self.denied_pubkeys.contains(pubkey)
+            {
+                banned.insert(*pubkey, report_type.clone());
+            }
+        }
+
+        banned
+    }
+
+    /// Admit the pubkeys invited by `inviter` as long as it has quota left
+    ///
+    /// Pubkeys that are already allowed or denied are skipped. Returns the pubkeys that were admitted
+    /// with the allow list and invites to publish.
+    pub fn invite_pubkeys(
+        &mut self,
+        inviter: XOnlyPublicKey,
+        invitees: &HashSet<XOnlyPublicKey>,
+        quota: usize,
+    ) -> Result<(HashSet<XOnlyPublicKey>, Vec<nostr_sdk::event::Event>)> {
+        let used = self.invites.get(&inviter).map_or(0, HashSet::len);
+
+        let invited: HashSet<XOnlyPublicKey> = invitees
+            .iter()
+            .filter(|p| {
+                !p.eq(&&inviter)
+                    && !self.allowed_pubkeys.contains(p)
+                    && !self.denied_pubkeys.contains(p)
+            })
+            .take(quota.saturating_sub(used))
+            .cloned()
+            .collect();
+
+        if invited.is_empty() {
+            return Ok((invited, Vec::new()));
+        }
+
+        self.invites
+            .entry(inviter)
+            .or_default()
+            .extend(invited.iter().cloned());
+
+        let mut events = self.admit(&invited, &Actor::Pubkey(inviter), ChangeSource::Invite)?;
+        events.push(self.data_event(INVITES, &self.invites)?);
+
+        Ok((invited, events))
+    }
+
+    /// Pubkeys invited by `pubkey`, directly or by one of its invitees
+    pub fn invite_subtree(&self, pubkey: &XOnlyPublicKey) -> HashSet<XOnlyPublicKey> {
+        let mut subtree = HashSet::new();
+        let mut pending = vec![*pubkey];
+
+        while let Some(inviter) = pending.pop() {
+            if let Some(invitees) = self.invites.get(&inviter) {
+                for invitee in invitees {
+                    if !invitee.eq(pubkey) && subtree.insert(*invitee) {
+                        pending.push(*invitee);
+                    }
+                }
+            }
+        }
+
+        subtree
+    }
+
+    pub fn get_invites(&self) -> HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>> {
+        self.invites.clone()
+    }
+
+    /// Record the vouches of `voucher` and admit the candidates vouched for by at least `threshold` members
+    ///
+    /// Only vouches from currently allowed members are counted, the vouches for admitted candidates are
+    /// cleared. Returns the pubkeys that were admitted with the allow list and vouches to publish.
+    pub fn vouch_pubkeys(
+        &mut self,
+        voucher: XOnlyPublicKey,
+        candidates: &HashSet<XOnlyPublicKey>,
+        threshold: usize,
+    ) -> Result<(HashSet<XOnlyPublicKey>, Vec<nostr_sdk::event::Event>)> {
+        let vouch_count: usize = self.vouches.values().map(HashSet::len).sum();
+
+        for candidate in candidates {
+            if candidate.eq(&voucher)
+                || self.allowed_pubkeys.contains(candidate)
+                || self.denied_pubkeys.contains(candidate)
+            {
+                continue;
+            }
+
+            self.vouches.entry(*candidate).or_default().insert(voucher);
+        }
+
+        let promoted: HashSet<XOnlyPublicKey> = candidates
+            .iter()
+            .filter(|c| !self.allowed_pubkeys.contains(c) && !self.denied_pubkeys.contains(c))
+            .filter(|c| {
+                self.vouches.get(c).map_or(0, |vouchers| {
+                    vouchers
+                        .iter()
+                        .filter(|v| self.allowed_pubkeys.contains(v))
+                        .count()
+                }) >= threshold.max(1)
+            })
+            .cloned()
+            .collect();
+
+        let mut events = Vec::new();
+        if !promoted.is_empty() {
+            self.vouches.retain(|c, _| !promoted.contains(c));
+            events = self.admit(
+                &promoted,
+                &Actor::System("vouches".to_string()),
+                ChangeSource::Vouch,
+            )?;
+        }
+        if self.vouches.values().map(HashSet::len).sum::<usize>() != vouch_count {
+            events.push(self.data_event(VOUCHES, &self.vouches)?);
+        }
+
+        Ok((promoted, events))
+    }
+
+    pub fn get_vouches(&self) -> HashMap<XOnlyPublicKey, HashSet<XOnlyPublicKey>> {
+        self.vouches.clone()
+    }
+
+    pub fn set_content_rules(&mut self, rules: Vec<ContentRule>) -> Result<()> {
+        self.content_filter = ContentFilter::new(rules)?;
 
         Ok(())
     }
 
+    pub fn get_content_rules(&self) -> Vec<ContentRule> {
+        self.content_filter.rules()
+    }
+
+    /// Add or replace a content rule and publish the rules
+    pub async fn insert_content_rule(&mut self, rule: ContentRule) -> Result<()> {
+        self.content_filter.insert(rule)?;
+        self.publish_data(CONTENT_RULES, &self.get_content_rules())
+            .await
+    }
+
+    /// Remove the content rule with `name` and publish the rules, returns whether it existed
+    pub async fn remove_content_rule(&mut self, name: &str) -> Result<bool> {
+        if !self.content_filter.remove(name) {
+            return Ok(false);
+        }
+        self.publish_data(CONTENT_RULES, &self.get_content_rules())
+            .await?;
+
+        Ok(true)
+    }
+
+    pub fn set_kind_rules(&mut self, rules: Vec<KindRule>) {
+        self.kind_rules = KindRules::new(rules);
+    }
+
+    pub fn get_kind_rules(&self) -> Vec<KindRule> {
+        self.kind_rules.rules()
+    }
+
+    /// Add or replace a kind rule and publish the rules
+    pub async fn insert_kind_rule(&mut self, rule: KindRule) -> Result<()> {
+        self.kind_rules.insert(rule);
+        self.publish_data(KIND_RULES, &self.get_kind_rules()).await
+    }
+
+    /// Remove the kind rule with `name` and publish the rules, returns whether it existed
+    pub async fn remove_kind_rule(&mut self, name: &str) -> Result<bool> {
+        if !self.kind_rules.remove(name) {
+            return Ok(false);
+        }
+        self.publish_data(KIND_RULES, &self.get_kind_rules())
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Whether any of the pubkeys is an allowed member
+    pub fn addresses_member(&self, pubkeys: &HashSet<XOnlyPublicKey>) -> bool {
+        pubkeys.iter().any(|p| self.allowed_pubkeys.contains(p))
+    }
+
+    /// Count an inbound event from `sender`, returns false if it exceeds `limit` events per minute
+    pub fn record_inbound(&mut self, sender: XOnlyPublicKey, limit: u32) -> bool {
+        let now = unix_time();
+
+        if self.inbound_senders.len() > 10_000 {
+            self.inbound_senders
+                .retain(|_, (window_start, _)| now < *window_start + 60);
+        }
+
+        let (window_start, count) = self.inbound_senders.entry(sender).or_insert((now, 0));
+        if now >= *window_start + 60 {
+            *window_start = now;
+            *count = 0;
+        }
+
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+
+        true
+    }
+
+    /// Add event ids and content hashes to the blocklist and publish it
+    pub async fn block(
+        &mut self,
+        events: &HashSet<String>,
+        hashes: &HashSet<String>,
+    ) -> Result<()> {
+        self.blocked_events
+            .extend(events.iter().map(|e| e.to_lowercase()));
+        self.blocked_hashes
+            .extend(hashes.iter().map(|h| h.to_lowercase()));
+
+        self.publish_data(BLOCKLIST, &self.get_blocklist()).await
+    }
+
+    /// Remove event ids and content hashes from the blocklist and publish it
+    pub async fn unblock(
+        &mut self,
+        events: &HashSet<String>,
+        hashes: &HashSet<String>,
+    ) -> Result<()> {
+        for event in events {
+            self.blocked_events.remove(&event.to_lowercase());
+        }
+        for hash in hashes {
+            self.blocked_hashes.remove(&hash.to_lowercase());
+        }
+
+        self.publish_data(BLOCKLIST, &self.get_blocklist()).await
+    }
+
+    pub fn get_blocklist(&self) -> Blocklist {
+        Blocklist {
+            events: self.blocked_events.clone(),
+            hashes: self.blocked_hashes.clone(),
+        }
+    }
+
+    /// Whether the event, its content or an event it reposts or quotes is blocked
+    pub fn is_blocked(&self, event: &Event) -> bool {
+        if self.blocked_events.contains(&::hex::encode(&event.id))
+            || self
+                .blocked_hashes
+                .contains(&utils::content_hash(&event.content))
+        {
+            return true;
+        }
+
+        let is_repost = event.kind.eq(&nostr_sdk::Kind::Repost.as_u64()) || event.kind.eq(&16);
+        let quoted = event
+            .tags
+            .iter()
+            .filter(|t| match t.values.first().map(String::as_str) {
+                Some("e") => is_repost,
+                Some("q") => true,
+                _ => false,
+            })
+            .filter_map(|t| t.values.get(1))
+            .any(|id| self.blocked_events.contains(&id.to_lowercase()));
+
+        quoted
+            || utils::mentioned_events(&event.content)
+                .iter()
+                .any(|id| self.blocked_events.contains(id))
+    }
+
+    /// Add an api key, replacing the key with the same id
+    pub fn insert_api_key(&mut self, key: ApiKey) {
+        self.api_keys.insert(key.id.clone(), key);
+    }
+
+    /// Remove the api key with `id`, returns whether it existed
+    pub fn remove_api_key(&mut self, id: &str) -> bool {
+        self.api_keys.remove(id).is_some()
+    }
+
+    /// Publish the api keys created at runtime, to be called after creating, rotating or revoking one
+    pub async fn save_api_keys(&self) -> Result<()> {
+        let keys: Vec<StoredApiKey> = self
+            .api_keys
+            .values()
+            .filter(|k| !k.from_config)
+            .map(|k| StoredApiKey {
+                id: k.id.clone(),
+                hash: k.hash.clone(),
+                scope: k.scope,
+                expires_at: k.expires_at,
+            })
+            .collect();
+
+        self.publish_data(API_KEYS, &keys).await
+    }
+
+    /// Add the restored runtime keys, keys set in the config take precedence
+    fn restore_api_keys(&mut self, keys: Vec<StoredApiKey>) {
+        for key in keys {
+            if self.api_keys.contains_key(&key.id) {
+                continue;
+            }
+            self.insert_api_key(ApiKey {
+                id: key.id,
+                hash: key.hash,
+                scope: key.scope,
+                expires_at: key.expires_at,
+                from_config: false,
+            });
+        }
+    }
+
+    pub fn get_api_keys(&self) -> Vec<ApiKey> {
+        self.api_keys.values().cloned().collect()
+    }
+
+    /// Unexpired api key with the secret
+    pub fn find_api_key(&self, secret: &str) -> Option<ApiKey> {
+        self.api_keys
+            .values()
+            .find(|k| !k.is_expired() && k.matches(secret))
+            .cloned()
+    }
+
     pub fn get_users(&self) -> Users {
         Users {
             allow: Some(self.allowed_pubkeys.clone()),
@@ -151,6 +1264,59 @@ impl Repo {
         }
     }
 
+    /// Page of at most `limit` allowed or denied pubkeys ordered by the time they were added
+    ///
+    /// Pubkeys are filtered by `status` and by a hex or `npub` `prefix`. Returns the cursor of the
+    /// next page if there may be more pubkeys after this one.
+    pub fn list_users(
+        &self,
+        status: Option<UserStatus>,
+        prefix: Option<&str>,
+        cursor: Option<UserCursor>,
+        descending: bool,
+        limit: usize,
+    ) -> (Vec<UserEntry>, Option<UserCursor>) {
+        let prefix = prefix.map(str::to_lowercase);
+        let entries: Box<dyn Iterator<Item = &UserCursor>> = match (cursor, descending) {
+            (None, false) => Box::new(self.added_index.iter()),
+            (None, true) => Box::new(self.added_index.iter().rev()),
+            (Some(cursor), false) => Box::new(self.added_index.range((
+                std::ops::Bound::Excluded(cursor),
+                std::ops::Bound::Unbounded,
+            ))),
+            (Some(cursor), true) => Box::new(self.added_index.range(..cursor).rev()),
+        };
+
+        let page: Vec<UserEntry> = entries
+            .map(|(added_at, pubkey)| UserEntry {
+                pubkey: *pubkey,
+                status: self.status(pubkey),
+                added_at: *added_at,
+            })
+            .filter(|e| !e.status.eq(&UserStatus::Unknown))
+            .filter(|e| status.is_none_or(|s| s.eq(&e.status)))
+            .filter(|e| {
+                prefix.as_ref().is_none_or(|prefix| {
+                    if prefix.starts_with("npub1") {
+                        e.pubkey
+                            .to_bech32()
+                            .is_ok_and(|npub| npub.starts_with(prefix.as_str()))
+                    } else {
+                        e.pubkey.to_string().starts_with(prefix.as_str())
+                    }
+                })
+            })
+            .take(limit)
+            .collect();
+
+        let next = match page.last() {
+            Some(last) if page.len() == limit => Some((last.added_at, last.pubkey)),
+            _ => None,
+        };
+
+        (page, next)
+    }
+
     fn pubkeys_from_nostr(
         &self,
         event: nostr_sdk::event::Event,
@@ -190,6 +1356,40 @@ impl Repo {
         Ok(pubkeys)
     }
 
+    /// Expiry of the time limited denials saved in a deny list and the members among them
+    fn deny_expiry_from_nostr(
+        &self,
+        event: &nostr_sdk::event::Event,
+    ) -> (HashMap<XOnlyPublicKey, u64>, HashSet<XOnlyPublicKey>) {
+        let content = match decrypt(
+            &self.key.secret_key().unwrap(),
+            &self.key.public_key(),
+            &event.content,
+        ) {
+            Ok(content) => content,
+            Err(_) => return (HashMap::new(), HashSet::new()),
+        };
+        let tags: Vec<Vec<String>> = serde_json::from_str(&content).unwrap_or_default();
+
+        let mut deny_expiry = HashMap::new();
+        let mut members = HashSet::new();
+        for tag in tags
+            .iter()
+            .filter(|t| t.first().is_some_and(|k| k.eq("expiration")))
+        {
+            let expires_at = tag.get(1).and_then(|t| t.parse().ok());
+            let pubkey = tag.get(2).and_then(|p| XOnlyPublicKey::from_str(p).ok());
+            if let (Some(expires_at), Some(pubkey)) = (expires_at, pubkey) {
+                deny_expiry.insert(pubkey, expires_at);
+                if tag.get(3).is_some_and(|t| t.eq("allow")) {
+                    members.insert(pubkey);
+                }
+            }
+        }
+
+        (deny_expiry, members)
+    }
+
     pub async fn update_people(&mut self, event: Event) -> Result<()> {
         let mut encrypted_pubs = HashSet::new();
 
@@ -212,18 +1412,38 @@ impl Repo {
             }
         }
 
-        if let Some(t) = event.tags.get(0) {
-            if t.values.get(0).eq(&Some(&"d".to_string())) {
+        if let Some(t) = event.tags.first() {
+            if t.values.first().eq(&Some(&"d".to_string())) {
                 if t.values.get(1).eq(&Some(&"allow".to_string())) {
                     let mut allowed = pubkey_from_tags(event.tags)?;
 
                     allowed.extend(encrypted_pubs);
+                    let changed = allowed
+                        .symmetric_difference(&self.allowed_pubkeys)
+                        .cloned()
+                        .collect();
+                    let before = self.membership(&changed);
+                    let actor = Actor::Pubkey(self.key.public_key());
+                    self.reports.retain(|p, _| !changed.contains(p));
                     self.allowed_pubkeys = allowed;
+                    self.record(&changed, &actor);
+                    self.unindex(&changed);
+                    self.log_change(before, &actor, ChangeSource::NostrList);
                 } else if t.values.get(1).eq(&Some(&"deny".to_string())) {
                     let mut denied = pubkey_from_tags(event.tags)?;
 
                     denied.extend(encrypted_pubs);
+                    let changed = denied
+                        .symmetric_difference(&self.denied_pubkeys)
+                        .cloned()
+                        .collect();
+                    let before = self.membership(&changed);
+                    let actor = Actor::Pubkey(self.key.public_key());
+                    self.reports.retain(|p, _| !changed.contains(p));
                     self.denied_pubkeys = denied;
+                    self.record(&changed, &actor);
+                    self.unindex(&changed);
+                    self.log_change(before, &actor, ChangeSource::NostrList);
                 }
             }
         }
@@ -233,11 +1453,32 @@ impl Repo {
         Ok(())
     }
 
+    /// Whether any of the pubkeys is currently denied
+    pub fn any_denied(&self, pubkeys: &HashSet<XOnlyPublicKey>) -> bool {
+        let now = unix_time();
+        pubkeys.iter().any(|p| {
+            self.denied_pubkeys.contains(p)
+                && self
+                    .deny_expiry
+                    .get(p)
+                    .is_none_or(|expires_at| *expires_at > now)
+        })
+    }
+
     pub async fn get_user_status(&self, pubkey: XOnlyPublicKey) -> UserStatus {
         log::debug!("{:?}", pubkey);
-        if self.allowed_pubkeys.contains(&pubkey) {
+        self.status(&pubkey)
+    }
+
+    fn status(&self, pubkey: &XOnlyPublicKey) -> UserStatus {
+        if self.allowed_pubkeys.contains(pubkey) {
             return UserStatus::Allowed;
-        } else if self.denied_pubkeys.contains(&pubkey) {
+        } else if self.denied_pubkeys.contains(pubkey) {
+            if let Some(expires_at) = self.deny_expiry.get(pubkey) {
+                if *expires_at <= unix_time() {
+                    return UserStatus::Unknown;
+                }
+            }
             return UserStatus::Denied;
         }
 
@@ -341,3 +1582,597 @@ mod tests {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use crate::nauthz_grpc::event::TagEntry;
+
+    use super::*;
+
+    /// Repo with pubkeys allowed at 10, 20, 30, 40 and 50 and denied at 15, 25 and 35
+    fn repo_with_users() -> (Repo, Vec<XOnlyPublicKey>, Vec<XOnlyPublicKey>) {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let actor = Actor::System("test".to_string());
+
+        let allowed: Vec<_> = (1..=5).map(|_| Keys::generate().public_key()).collect();
+        let denied: Vec<_> = (1..=3).map(|_| Keys::generate().public_key()).collect();
+        for (i, pubkey) in allowed.iter().enumerate() {
+            repo.allowed_pubkeys.insert(*pubkey);
+            repo.record_at(&HashSet::from([*pubkey]), &actor, 10 * (i as u64 + 1));
+        }
+        for (i, pubkey) in denied.iter().enumerate() {
+            repo.denied_pubkeys.insert(*pubkey);
+            repo.record_at(&HashSet::from([*pubkey]), &actor, 10 * (i as u64 + 1) + 5);
+        }
+
+        (repo, allowed, denied)
+    }
+
+    fn times(page: &[UserEntry]) -> Vec<u64> {
+        page.iter().map(|e| e.added_at).collect()
+    }
+
+    #[test]
+    fn test_list_users_pages_in_order() {
+        let (repo, _, _) = repo_with_users();
+
+        let (page, next) = repo.list_users(None, None, None, false, 3);
+        assert_eq!(times(&page), vec![10, 15, 20]);
+        assert_eq!(next, Some((20, page[2].pubkey)));
+
+        let (page, next) = repo.list_users(None, None, next, false, 3);
+        assert_eq!(times(&page), vec![25, 30, 35]);
+
+        let (page, next) = repo.list_users(None, None, next, false, 3);
+        assert_eq!(times(&page), vec![40, 50]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_list_users_full_last_page() {
+        let (repo, _, _) = repo_with_users();
+
+        let (page, next) = repo.list_users(None, None, None, false, 4);
+        assert_eq!(page.len(), 4);
+        let (page, next) = repo.list_users(None, None, next, false, 4);
+        assert_eq!(times(&page), vec![30, 35, 40, 50]);
+        assert_eq!(next, Some((50, page[3].pubkey)));
+
+        let (page, next) = repo.list_users(None, None, next, false, 4);
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_list_users_descending_with_cursor() {
+        let (repo, _, _) = repo_with_users();
+
+        let (page, next) = repo.list_users(None, None, None, true, 3);
+        assert_eq!(times(&page), vec![50, 40, 35]);
+
+        let (page, next) = repo.list_users(None, None, next, true, 3);
+        assert_eq!(times(&page), vec![30, 25, 20]);
+
+        let (page, next) = repo.list_users(None, None, next, true, 3);
+        assert_eq!(times(&page), vec![15, 10]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_list_users_status_filter_with_cursor() {
+        let (repo, _, denied) = repo_with_users();
+
+        let (page, next) = repo.list_users(Some(UserStatus::Denied), None, None, false, 2);
+        assert_eq!(times(&page), vec![15, 25]);
+        assert!(page.iter().all(|e| e.status.eq(&UserStatus::Denied)));
+
+        let (page, next) = repo.list_users(Some(UserStatus::Denied), None, next, false, 2);
+        assert_eq!(times(&page), vec![35]);
+        assert_eq!(page[0].pubkey, denied[2]);
+        assert_eq!(next, None);
+
+        let (page, _) = repo.list_users(
+            Some(UserStatus::Allowed),
+            None,
+            Some((35, denied[2])),
+            true,
+            10,
+        );
+        assert_eq!(times(&page), vec![30, 20, 10]);
+    }
+
+    #[test]
+    fn test_list_users_prefix_filter_with_cursor() {
+        let (repo, allowed, _) = repo_with_users();
+        let hex = allowed[3].to_string();
+
+        let (page, next) = repo.list_users(None, Some(&hex[..16]), None, false, 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].pubkey, allowed[3]);
+        assert_eq!(next, Some((40, allowed[3])));
+
+        let (page, next) = repo.list_users(None, Some(&hex[..16]), next, false, 1);
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+
+        let npub = allowed[3].to_bech32().unwrap();
+        let (page, _) = repo.list_users(None, Some(&npub[..20]), Some((10, allowed[0])), false, 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].pubkey, allowed[3]);
+
+        let (page, _) = repo.list_users(None, Some(&hex[..16]), Some((40, allowed[3])), true, 10);
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    fn test_list_users_skips_unknown_pubkeys() {
+        let (mut repo, allowed, denied) = repo_with_users();
+        repo.allowed_pubkeys.remove(&allowed[0]);
+        repo.deny_expiry.insert(denied[0], unix_time() - 1);
+
+        let (page, _) = repo.list_users(None, None, None, false, 3);
+        assert_eq!(times(&page), vec![20, 25, 30]);
+    }
+
+    #[test]
+    fn test_list_users_keeps_creation_time_on_change() {
+        let (mut repo, allowed, _) = repo_with_users();
+        let actor = Actor::System("test".to_string());
+        repo.record_at(&HashSet::from([allowed[0]]), &actor, 60);
+
+        let (page, _) = repo.list_users(None, None, None, false, 1);
+        assert_eq!(times(&page), vec![10]);
+        assert_eq!(page[0].pubkey, allowed[0]);
+        assert_eq!(repo.added_index.len(), 8);
+    }
+
+    #[test]
+    fn test_restore_records_keeps_metadata() {
+        let (mut repo, allowed, denied) = repo_with_users();
+        repo.set_note(&HashSet::from([denied[0]]), "spam");
+        repo.set_labels(
+            &HashSet::from([allowed[1]]),
+            &BTreeSet::from(["staff".to_string()]),
+        );
+        let mut records = repo.records.clone();
+        records.remove(&allowed[0]);
+        let unlisted = Keys::generate().public_key();
+        records.insert(unlisted, records[&allowed[2]].clone());
+
+        repo.records.clear();
+        repo.added_index.clear();
+        repo.restore_records(records, 100, 200);
+
+        assert_eq!(repo.records[&allowed[0]].created_at, 100);
+        assert_eq!(repo.records[&allowed[1]].created_at, 20);
+        assert!(repo.records[&allowed[1]].labels.contains("staff"));
+        assert_eq!(repo.records[&denied[0]].created_at, 15);
+        assert_eq!(repo.records[&denied[0]].note.as_deref(), Some("spam"));
+
+        let (page, _) = repo.list_users(None, None, None, false, 10);
+        assert_eq!(times(&page), vec![15, 20, 25, 30, 35, 40, 50, 100]);
+    }
+
+    #[test]
+    fn test_remove_prunes_index() {
+        let (mut repo, allowed, denied) = repo_with_users();
+        let actor = Actor::System("test".to_string());
+        let pubkeys = HashSet::from([allowed[0], denied[0]]);
+
+        repo.remove(&pubkeys, &actor, ChangeSource::Http).unwrap();
+        assert_eq!(repo.added_index.len(), 6);
+        assert!(repo.added_index.iter().all(|(_, p)| !pubkeys.contains(p)));
+
+        repo.admit(&HashSet::from([allowed[0]]), &actor, ChangeSource::Http)
+            .unwrap();
+        let (page, _) = repo.list_users(None, None, None, true, 1);
+        assert_eq!(page[0].pubkey, allowed[0]);
+        assert!(page[0].added_at > 50);
+        assert_eq!(repo.added_index.len(), 7);
+    }
+
+    #[test]
+    fn test_invite_pubkeys_stops_at_quota() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let inviter = Keys::generate().public_key();
+        let member = Keys::generate().public_key();
+        let denied = Keys::generate().public_key();
+        repo.allowed_pubkeys.extend([inviter, member]);
+        repo.denied_pubkeys.insert(denied);
+
+        let invitees: HashSet<_> = (0..3).map(|_| Keys::generate().public_key()).collect();
+        let mut skipped = invitees.clone();
+        skipped.extend([inviter, member, denied]);
+        let (invited, events) = repo.invite_pubkeys(inviter, &skipped, 2).unwrap();
+
+        assert_eq!(invited.len(), 2);
+        assert!(invited.is_subset(&invitees));
+        assert!(invited.iter().all(|p| repo.allowed_pubkeys.contains(p)));
+        assert!(repo.denied_pubkeys.contains(&denied));
+        assert_eq!(repo.invites[&inviter], invited);
+        assert_eq!(events.len(), 3);
+
+        let (invited, events) = repo.invite_pubkeys(inviter, &invitees, 2).unwrap();
+        assert!(invited.is_empty());
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_invite_subtree_revocation() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let [root, child, grandchild, other] = [(); 4].map(|_| Keys::generate().public_key());
+        repo.allowed_pubkeys.extend([root, other]);
+
+        repo.invite_pubkeys(root, &HashSet::from([child]), 1)
+            .unwrap();
+        repo.invite_pubkeys(child, &HashSet::from([grandchild]), 1)
+            .unwrap();
+        // An invite cycle back to the root does not add it to its own subtree
+        repo.invites.entry(grandchild).or_default().insert(root);
+
+        let mut revoked = repo.invite_subtree(&root);
+        assert_eq!(revoked, HashSet::from([child, grandchild]));
+        assert_eq!(
+            repo.invite_subtree(&child),
+            HashSet::from([grandchild, root])
+        );
+
+        revoked.insert(root);
+        let actor = Actor::System("test".to_string());
+        repo.deny(&revoked, None, &actor, ChangeSource::Http)
+            .unwrap();
+
+        assert!(revoked.iter().all(|p| repo.denied_pubkeys.contains(p)));
+        assert!(repo.allowed_pubkeys.contains(&other));
+        assert_eq!(repo.allowed_pubkeys.len(), 1);
+    }
+
+    #[test]
+    fn test_vouch_pubkeys_clears_vouches_on_promotion() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let [first, second, candidate] = [(); 3].map(|_| Keys::generate().public_key());
+        repo.allowed_pubkeys.extend([first, second]);
+        let candidates = HashSet::from([candidate]);
+
+        let (promoted, events) = repo.vouch_pubkeys(first, &candidates, 2).unwrap();
+        assert!(promoted.is_empty());
+        assert_eq!(events.len(), 1);
+        assert_eq!(repo.vouches[&candidate], HashSet::from([first]));
+
+        let (promoted, _) = repo.vouch_pubkeys(first, &candidates, 2).unwrap();
+        assert!(promoted.is_empty());
+
+        let (promoted, events) = repo.vouch_pubkeys(second, &candidates, 2).unwrap();
+        assert_eq!(promoted, candidates);
+        assert_eq!(events.len(), 3);
+        assert!(repo.allowed_pubkeys.contains(&candidate));
+        assert!(!repo.vouches.contains_key(&candidate));
+
+        // A removed candidate has to be vouched for again
+        repo.allowed_pubkeys.remove(&candidate);
+        let (promoted, _) = repo.vouch_pubkeys(first, &candidates, 2).unwrap();
+        assert!(promoted.is_empty());
+    }
+
+    fn report_settings(threshold: usize) -> Reports {
+        Reports {
+            enabled: true,
+            threshold,
+            thresholds: HashMap::from([("illegal".to_string(), 1)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tally_reports_thresholds() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let settings = report_settings(2);
+        let [first, second, spammer, criminal] = [(); 4].map(|_| Keys::generate().public_key());
+        let reports = HashMap::from([
+            (spammer, "spam".to_string()),
+            (criminal, "illegal".to_string()),
+        ]);
+
+        let banned = repo.tally_reports(first, &reports, &settings, &HashSet::new());
+        assert_eq!(banned, HashMap::from([(criminal, "illegal".to_string())]));
+
+        // A reporter is only counted once
+        let banned = repo.tally_reports(first, &reports, &settings, &HashSet::new());
+        assert!(!banned.contains_key(&spammer));
+
+        let banned = repo.tally_reports(second, &reports, &settings, &HashSet::new());
+        assert_eq!(banned[&spammer], "spam");
+
+        let banned = repo.tally_reports(second, &reports, &report_settings(0), &HashSet::new());
+        assert!(!banned.contains_key(&spammer));
+    }
+
+    #[test]
+    fn test_tally_reports_skips_self_exempt_and_denied() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let settings = report_settings(1);
+        let [reporter, admin, denied] = [(); 3].map(|_| Keys::generate().public_key());
+        repo.denied_pubkeys.insert(denied);
+        let reports = HashMap::from([
+            (reporter, "spam".to_string()),
+            (admin, "spam".to_string()),
+            (denied, "spam".to_string()),
+        ]);
+
+        let banned = repo.tally_reports(reporter, &reports, &settings, &HashSet::from([admin]));
+
+        assert!(banned.is_empty());
+        assert!(!repo.reports.contains_key(&reporter));
+        assert!(!repo.reports.contains_key(&admin));
+    }
+
+    #[test]
+    fn test_report_ban_clears_tally() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let [first, second, reported] = [(); 3].map(|_| Keys::generate().public_key());
+        let reports = HashMap::from([(reported, "spam".to_string())]);
+
+        let (banned, events) = repo
+            .report_pubkeys(first, &reports, &report_settings(1), &HashSet::new())
+            .unwrap();
+        assert!(banned.contains_key(&reported));
+        assert_eq!(events.len(), 2);
+        assert!(!repo.reports.contains_key(&reported));
+
+        // Once unbanned the pubkey starts from a clean tally
+        let actor = Actor::System("test".to_string());
+        repo.admit(&HashSet::from([reported]), &actor, ChangeSource::Http)
+            .unwrap();
+        let (banned, _) = repo
+            .report_pubkeys(second, &reports, &report_settings(2), &HashSet::new())
+            .unwrap();
+        assert!(banned.is_empty());
+        assert_eq!(repo.reports[&reported]["spam"], HashSet::from([second]));
+    }
+
+    #[test]
+    fn test_expired_report_ban_restores_member() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let [reporter, member, unknown] = [(); 3].map(|_| Keys::generate().public_key());
+        repo.allowed_pubkeys.insert(member);
+        let settings = Reports {
+            ban_duration: Some(60),
+            ..report_settings(1)
+        };
+        let reports = HashMap::from([(member, "spam".to_string()), (unknown, "spam".to_string())]);
+
+        let (_, events) = repo
+            .report_pubkeys(reporter, &reports, &settings, &HashSet::new())
+            .unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(repo.status(&member), UserStatus::Denied);
+        assert_eq!(repo.suspended_members, HashSet::from([member]));
+
+        let (expired, _) = repo.expire_denials(unix_time()).unwrap();
+        assert!(expired.is_empty());
+
+        let (expired, events) = repo.expire_denials(unix_time() + 60).unwrap();
+        assert_eq!(expired, HashSet::from([member, unknown]));
+        assert_eq!(events.len(), 3);
+        assert_eq!(repo.status(&member), UserStatus::Allowed);
+        assert_eq!(repo.status(&unknown), UserStatus::Unknown);
+        assert!(repo.suspended_members.is_empty());
+    }
+
+    #[test]
+    fn test_permanent_deny_ends_suspension() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let member = Keys::generate().public_key();
+        let pubkeys = HashSet::from([member]);
+        let actor = Actor::System("test".to_string());
+        repo.allowed_pubkeys.insert(member);
+
+        repo.deny(
+            &pubkeys,
+            Some(unix_time() + 60),
+            &actor,
+            ChangeSource::Report,
+        )
+        .unwrap();
+        assert!(repo.suspended_members.contains(&member));
+
+        repo.deny(&pubkeys, None, &actor, ChangeSource::Http)
+            .unwrap();
+        assert!(repo.suspended_members.is_empty());
+        let (expired, _) = repo.expire_denials(unix_time() + 60).unwrap();
+        assert!(expired.is_empty());
+        assert_eq!(repo.status(&member), UserStatus::Denied);
+    }
+
+    #[test]
+    fn test_record_inbound_limits_each_sender_per_minute() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let sender = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+
+        assert!(repo.record_inbound(sender, 2));
+        assert!(repo.record_inbound(sender, 2));
+        assert!(!repo.record_inbound(sender, 2));
+        assert!(repo.record_inbound(other, 2));
+
+        // A new window starts a minute after the first event of the last one
+        repo.inbound_senders.get_mut(&sender).unwrap().0 -= 60;
+        assert!(repo.record_inbound(sender, 2));
+        assert_eq!(repo.inbound_senders[&sender].1, 1);
+    }
+
+    fn event(kind: u64, content: &str, tags: &[&[&str]]) -> Event {
+        Event {
+            id: vec![0x11; 32],
+            pubkey: vec![],
+            created_at: 0,
+            kind,
+            content: content.to_string(),
+            tags: tags
+                .iter()
+                .map(|t| TagEntry {
+                    values: t.iter().map(|v| v.to_string()).collect(),
+                })
+                .collect(),
+            sig: vec![],
+        }
+    }
+
+    #[test]
+    fn test_is_blocked_by_id_and_content_hash() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        assert!(!repo.is_blocked(&event(1, "Buy  NOW", &[])));
+
+        repo.blocked_hashes.insert(utils::content_hash("buy now"));
+        assert!(repo.is_blocked(&event(1, "Buy  NOW\n", &[])));
+        assert!(!repo.is_blocked(&event(1, "buy later", &[])));
+
+        repo.blocked_events.insert("11".repeat(32));
+        assert!(repo.is_blocked(&event(1, "", &[])));
+    }
+
+    #[test]
+    fn test_is_blocked_by_reposted_or_quoted_event() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let blocked = "ab".repeat(32);
+        repo.blocked_events.insert(blocked.clone());
+        let upper = blocked.to_uppercase();
+
+        assert!(repo.is_blocked(&event(6, "", &[&["e", &upper]])));
+        assert!(repo.is_blocked(&event(16, "", &[&["e", &blocked]])));
+        assert!(repo.is_blocked(&event(1, "", &[&["q", &blocked]])));
+        // Replies are not reposts
+        assert!(!repo.is_blocked(&event(1, "", &[&["e", &blocked]])));
+
+        let note = EventId::from_hex(&blocked).unwrap().to_bech32().unwrap();
+        assert!(repo.is_blocked(&event(1, &format!("look nostr:{note}"), &[])));
+        assert!(!repo.is_blocked(&event(1, &note, &[])));
+    }
+
+    #[test]
+    fn test_remove_makes_pubkeys_unknown() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let actor = Actor::ApiKey("test".to_string());
+        let [member, banned, expiring, kept] = [(); 4].map(|_| Keys::generate().public_key());
+        repo.allowed_pubkeys.extend([member, kept]);
+        repo.denied_pubkeys.insert(banned);
+        repo.deny(
+            &HashSet::from([expiring]),
+            Some(unix_time() + 60),
+            &actor,
+            ChangeSource::Report,
+        )
+        .unwrap();
+        repo.reports.entry(member).or_default();
+
+        let removed = HashSet::from([member, banned, expiring]);
+        let events = repo.remove(&removed, &actor, ChangeSource::Http).unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert!(removed
+            .iter()
+            .all(|p| repo.status(p).eq(&UserStatus::Unknown)));
+        assert_eq!(repo.status(&kept), UserStatus::Allowed);
+        assert!(repo.deny_expiry.is_empty());
+        assert!(repo.reports.is_empty());
+        assert_eq!(repo.get_record(&member).unwrap().updated_by, actor);
+
+        let change = repo.changes.back().unwrap();
+        assert_eq!(change.source, ChangeSource::Http);
+        assert_eq!(change.allow.removed, HashSet::from([member]));
+        assert_eq!(change.deny.removed, HashSet::from([banned, expiring]));
+    }
+
+    #[test]
+    fn test_revert_keeps_lists_exclusive() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let a = Actor::System("a".to_string());
+        let b = Actor::System("b".to_string());
+        let pubkey = Keys::generate().public_key();
+        let pubkeys = HashSet::from([pubkey]);
+        repo.allowed_pubkeys.insert(pubkey);
+
+        let before = repo.membership(&pubkeys);
+        repo.allowed_pubkeys.remove(&pubkey);
+        let removed = repo.log_change(before, &a, ChangeSource::Http).unwrap();
+
+        let before = repo.membership(&pubkeys);
+        repo.denied_pubkeys.insert(pubkey);
+        repo.deny_expiry.insert(pubkey, unix_time() + 60);
+        repo.log_change(before, &b, ChangeSource::Http).unwrap();
+
+        let revert = repo.apply_revert(&removed, &a).unwrap();
+
+        assert!(repo.allowed_pubkeys.contains(&pubkey));
+        assert!(!repo.denied_pubkeys.contains(&pubkey));
+        assert!(!repo.deny_expiry.contains_key(&pubkey));
+        assert_eq!(revert.allow.added, pubkeys);
+        assert_eq!(revert.deny.removed, pubkeys);
+    }
+
+    #[test]
+    fn test_revert_deny_takes_pubkey_off_allow_list() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let actor = Actor::System("test".to_string());
+        let pubkey = Keys::generate().public_key();
+        let pubkeys = HashSet::from([pubkey]);
+        repo.denied_pubkeys.insert(pubkey);
+
+        let before = repo.membership(&pubkeys);
+        repo.denied_pubkeys.remove(&pubkey);
+        let unbanned = repo.log_change(before, &actor, ChangeSource::Http).unwrap();
+
+        let before = repo.membership(&pubkeys);
+        repo.allowed_pubkeys.insert(pubkey);
+        repo.log_change(before, &actor, ChangeSource::Http).unwrap();
+
+        repo.apply_revert(&unbanned, &actor).unwrap();
+
+        assert!(repo.denied_pubkeys.contains(&pubkey));
+        assert!(!repo.allowed_pubkeys.contains(&pubkey));
+    }
+
+    #[test]
+    fn test_change_log_drops_oldest_changes() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        repo.change_log_size = 2;
+        let actor = Actor::System("test".to_string());
+
+        for _ in 0..3 {
+            let pubkeys = HashSet::from([Keys::generate().public_key()]);
+            let before = repo.membership(&pubkeys);
+            repo.allowed_pubkeys.extend(&pubkeys);
+            repo.log_change(before, &actor, ChangeSource::Http);
+        }
+
+        let ids: Vec<_> = repo.get_changes(0).iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(repo.get_change(1).is_none());
+    }
+
+    #[test]
+    fn test_restore_api_keys_keeps_config_keys() {
+        let mut repo = Repo::new(Keys::generate(), HashSet::new()).unwrap();
+        let (config, _) = ApiKey::generate("default", Scope::Admin, None);
+        repo.insert_api_key(ApiKey {
+            from_config: true,
+            ..config.clone()
+        });
+
+        let stored = |id: &str, hash: &str| StoredApiKey {
+            id: id.to_string(),
+            hash: hash.to_string(),
+            scope: Scope::Read,
+            expires_at: Some(100),
+        };
+        repo.restore_api_keys(vec![stored("default", "aa"), stored("reader", "bb")]);
+
+        assert_eq!(repo.api_keys["default"].hash, config.hash);
+        assert!(repo.api_keys["default"].from_config);
+        let reader = &repo.api_keys["reader"];
+        assert_eq!(reader.hash, "bb");
+        assert_eq!(reader.scope, Scope::Read);
+        assert_eq!(reader.expires_at, Some(100));
+        assert!(!reader.from_config);
+    }
+}
@@ -0,0 +1,293 @@
+//! TLS for the http and grpc listeners, reloading certificates when their files change
+
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+
+/// How often certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+/// Pause after a failed accept, such as when out of file descriptors, before accepting again
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// Time a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM files a TLS listener is configured with
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA client certificates have to be signed by, if clients are authenticated
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Files of a listener, TLS is disabled if neither `cert` nor `key` is set
+    pub fn from_settings(
+        cert: &Option<String>,
+        key: &Option<String>,
+        client_ca: &Option<String>,
+    ) -> Result<Option<Self>> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: client_ca.as_ref().map(PathBuf::from),
+            })),
+            (None, None) if client_ca.is_none() => Ok(None),
+            _ => Err(anyhow!("TLS needs both a certificate and a key")),
+        }
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Server config advertising `alpn` protocols
+    pub fn server_config(&self, alpn: &[&[u8]]) -> Result<ServerConfig> {
+        let certs: Vec<Certificate> =
+            rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+
+        let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&self.key)?))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or(anyhow!("No private key in {}", self.key.display()))?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let mut config = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?))? {
+                    roots.add(&Certificate(cert))?;
+                }
+                builder
+                    .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                    .with_single_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+        };
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+        Ok(config)
+    }
+
+    /// Rebuild the server config and pass it to `apply` whenever one of the files changes
+    ///
+    /// A config that fails to load is logged and the previous one is kept.
+    pub fn watch<F>(self, alpn: &'static [&'static [u8]], apply: F)
+    where
+        F: Fn(Arc<ServerConfig>) + Send + 'static,
+    {
+        let modified = |paths: &[PathBuf]| -> Vec<Option<SystemTime>> {
+            paths
+                .iter()
+                .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+
+        tokio::spawn(async move {
+            let paths = self.paths();
+            let mut last = modified(&paths);
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let current = modified(&paths);
+                if current.eq(&last) {
+                    continue;
+                }
+                last = current;
+
+                match self.server_config(alpn) {
+                    Ok(config) => {
+                        info!("Reloaded TLS certificate {}", self.cert.display());
+                        apply(Arc::new(config));
+                    }
+                    Err(err) => warn!("Could not reload TLS certificate: {err}"),
+                }
+            }
+        });
+    }
+}
+
+/// Accept TLS connections on `listener` with the latest config of `files`, for `tonic`'s `serve_with_incoming`
+pub fn incoming(
+    listener: TcpListener,
+    files: TlsFiles,
+) -> Result<ReceiverStream<std::io::Result<TlsStream<TcpStream>>>> {
+    const ALPN: &[&[u8]] = &[b"h2"];
+
+    let config = Arc::new(RwLock::new(Arc::new(files.server_config(ALPN)?)));
+    let current = config.clone();
+    files.watch(ALPN, move |reloaded| {
+        if let Ok(mut config) = current.write() {
+            *config = reloaded;
+        }
+    });
+
+    Ok(accept_tls(listener, config, HANDSHAKE_TIMEOUT))
+}
+
+/// Spawn the accept loop, connections that do not complete the handshake within `handshake_timeout` are dropped
+fn accept_tls(
+    listener: TcpListener,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+    handshake_timeout: Duration,
+) -> ReceiverStream<std::io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let tcp =
+                next_connection(|| async { listener.accept().await.map(|(tcp, _)| tcp) }).await;
+
+            let acceptor = match config.read() {
+                Ok(config) => TlsAcceptor::from(config.clone()),
+                Err(_) => continue,
+            };
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // Failed or stalled handshakes only affect their own connection
+                if let Ok(Ok(stream)) =
+                    tokio::time::timeout(handshake_timeout, acceptor.accept(tcp)).await
+                {
+                    let _ = sender.send(Ok(stream)).await;
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+/// Next accepted connection, pausing for `ACCEPT_BACKOFF` after each failed accept
+async fn next_connection<T, F, Fut>(mut accept: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    loop {
+        match accept().await {
+            Ok(connection) => return connection,
+            Err(err) => {
+                warn!("Could not accept grpc connection: {err}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::{ClientConfig, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn files() -> TlsFiles {
+        TlsFiles {
+            cert: "tests/data/cert.pem".into(),
+            key: "tests/data/key.pem".into(),
+            client_ca: None,
+        }
+    }
+
+    async fn listen(
+        handshake_timeout: Duration,
+    ) -> (
+        std::net::SocketAddr,
+        ReceiverStream<std::io::Result<TlsStream<TcpStream>>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(RwLock::new(Arc::new(
+            files().server_config(&[b"h2"]).unwrap(),
+        )));
+
+        (addr, accept_tls(listener, config, handshake_timeout))
+    }
+
+    fn connector() -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        let pem = std::fs::read("tests/data/cert.pem").unwrap();
+        for cert in rustls_pemfile::certs(&mut pem.as_slice()).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshake_does_not_block_others() {
+        let (addr, mut incoming) = listen(Duration::from_millis(200)).await;
+
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        let mut garbage = TcpStream::connect(addr).await.unwrap();
+        garbage.write_all(b"not a client hello").await.unwrap();
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let client = connector().connect(server_name, tcp).await.unwrap();
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        let accepted = tokio::time::timeout(Duration::from_secs(5), incoming.next())
+            .await
+            .unwrap();
+        assert!(accepted.unwrap().is_ok());
+
+        // The stalled connection is closed once its handshake times out
+        let read = tokio::time::timeout(Duration::from_secs(5), stalled.read(&mut [0; 1]))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        let next = tokio::time::timeout(Duration::from_millis(500), incoming.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_backs_off_after_errors() {
+        let attempts = AtomicUsize::new(0);
+        let started = tokio::time::Instant::now();
+
+        let connection = next_connection(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(std::io::Error::from_raw_os_error(24)),
+                n => Ok(n),
+            }
+        })
+        .await;
+
+        assert_eq!(connection, 2);
+        assert_eq!(started.elapsed(), ACCEPT_BACKOFF * 2);
+    }
+}
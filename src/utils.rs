@@ -1,5 +1,18 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::time::SystemTime;
 
+use anyhow::{anyhow, bail, Result};
+use nostr_sdk::hashes::{sha256, Hash};
+use nostr_sdk::key::XOnlyPublicKey;
+use nostr_sdk::prelude::{get_leading_zero_bits, FromBech32, Nip19Event};
+use nostr_sdk::EventId;
+use nostr_sdk::Profile;
+use serde::{Deserialize, Deserializer};
+
+use crate::nauthz_grpc::event::TagEntry;
+use crate::nauthz_grpc::Event;
+
 /// Seconds since 1970.
 #[must_use]
 pub fn unix_time() -> u64 {
@@ -8,3 +21,331 @@ pub fn unix_time() -> u64 {
         .map(|x| x.as_secs())
         .unwrap_or(0)
 }
+
+/// Pubkeys referenced by the `p` tags of an event
+pub fn tagged_pubkeys(tags: &[TagEntry]) -> HashSet<XOnlyPublicKey> {
+    tags.iter()
+        .filter(|t| t.values.first().map(String::as_str) == Some("p"))
+        .filter_map(|t| t.values.get(1))
+        .flat_map(|p| XOnlyPublicKey::from_str(p))
+        .collect()
+}
+
+/// Pubkeys reported by a NIP-56 report with their report type
+///
+/// The report type is taken from the `p` tag, falling back to the `e` tag and then to `other`.
+pub fn reported_pubkeys(tags: &[TagEntry]) -> HashMap<XOnlyPublicKey, String> {
+    let event_report_type = tags
+        .iter()
+        .filter(|t| t.values.first().map(String::as_str) == Some("e"))
+        .find_map(|t| t.values.get(2))
+        .filter(|r| !r.is_empty());
+
+    tags.iter()
+        .filter(|t| t.values.first().map(String::as_str) == Some("p"))
+        .filter_map(|t| {
+            let pubkey = XOnlyPublicKey::from_str(t.values.get(1)?).ok()?;
+            let report_type = t
+                .values
+                .get(2)
+                .filter(|r| !r.is_empty())
+                .or(event_report_type)
+                .cloned()
+                .unwrap_or("other".to_string());
+            Some((pubkey, report_type))
+        })
+        .collect()
+}
+
+/// NIP-13 difficulty of an event
+///
+/// If `require_nonce` is set the difficulty is capped by the target committed to in the `nonce` tag.
+pub fn pow_difficulty(event: &Event, require_nonce: bool) -> u8 {
+    let difficulty = get_leading_zero_bits(&event.id);
+
+    if !require_nonce {
+        return difficulty;
+    }
+
+    let target = event
+        .tags
+        .iter()
+        .filter(|t| t.values.first().map(String::as_str) == Some("nonce"))
+        .find_map(|t| t.values.get(2))
+        .and_then(|t| t.parse::<u8>().ok())
+        .unwrap_or(0);
+
+    difficulty.min(target)
+}
+
+/// Event ids and NIP-33 coordinates referenced by the `e` and `a` tags of an event
+pub fn referenced_events(tags: &[TagEntry]) -> (Vec<String>, Vec<String>) {
+    let values = |name: &str| -> Vec<String> {
+        tags.iter()
+            .filter(|t| t.values.first().map(String::as_str) == Some(name))
+            .filter_map(|t| t.values.get(1).cloned())
+            .collect()
+    };
+
+    (values("e"), values("a"))
+}
+
+/// Bech32 entities referenced as NIP-21 `nostr:` uris in content
+pub fn nostr_uris(content: &str) -> impl Iterator<Item = &str> {
+    content.split("nostr:").skip(1).filter_map(|rest| {
+        rest.split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .filter(|entity| !entity.is_empty())
+    })
+}
+
+/// Ids of the events quoted with `note` or `nevent` uris in content
+pub fn mentioned_events(content: &str) -> HashSet<String> {
+    nostr_uris(content)
+        .filter_map(|entity| {
+            EventId::from_bech32(entity)
+                .ok()
+                .or_else(|| Nip19Event::from_bech32(entity).ok().map(|e| e.event_id))
+        })
+        .map(|id| id.to_hex())
+        .collect()
+}
+
+/// Hex SHA256 of content with case and whitespace normalized
+pub fn content_hash(content: &str) -> String {
+    let normalized = content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+
+    sha256::Hash::hash(normalized.as_bytes()).to_string()
+}
+
+/// Pubkeys mentioned with `npub` or `nprofile` uris in content
+pub fn mentioned_pubkeys(content: &str) -> HashSet<XOnlyPublicKey> {
+    nostr_uris(content)
+        .filter_map(|entity| {
+            XOnlyPublicKey::from_bech32(entity)
+                .ok()
+                .or_else(|| Profile::from_bech32(entity).ok().map(|p| p.public_key))
+        })
+        .collect()
+}
+
+/// Author hints of the `e` tags of an event
+pub fn event_tag_authors(tags: &[TagEntry]) -> HashSet<XOnlyPublicKey> {
+    tags.iter()
+        .filter(|t| t.values.first().map(String::as_str) == Some("e"))
+        .filter_map(|t| t.values.get(4))
+        .flat_map(|p| XOnlyPublicKey::from_str(p))
+        .collect()
+}
+
+/// Parse 32 bytes of hex such as an event id or a SHA256, returned in lowercase
+pub fn parse_hex32(value: &str) -> Result<String> {
+    let value = value.trim().to_lowercase();
+    if value.len() != 64 || ::hex::decode(&value).is_err() {
+        bail!("Invalid hex: {value}");
+    }
+
+    Ok(value)
+}
+
+/// Parse a pubkey from hex, `npub` or `nprofile`
+pub fn parse_pubkey(pubkey: &str) -> Result<XOnlyPublicKey> {
+    let pubkey = pubkey.trim().trim_start_matches("nostr:");
+
+    XOnlyPublicKey::from_str(pubkey)
+        .ok()
+        .or_else(|| XOnlyPublicKey::from_bech32(pubkey).ok())
+        .or_else(|| Profile::from_bech32(pubkey).ok().map(|p| p.public_key))
+        .ok_or(anyhow!("Invalid pubkey: {pubkey}"))
+}
+
+/// Deserialize a set of hex, `npub` or `nprofile` pubkeys
+pub fn deserialize_pubkeys<'de, D>(deserializer: D) -> Result<HashSet<XOnlyPublicKey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|p| parse_pubkey(p).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Deserialize an optional set of hex, `npub` or `nprofile` pubkeys
+pub fn deserialize_optional_pubkeys<'de, D>(
+    deserializer: D,
+) -> Result<Option<HashSet<XOnlyPublicKey>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|pubkeys| {
+            pubkeys
+                .iter()
+                .map(|p| parse_pubkey(p).map_err(serde::de::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::ToBech32;
+
+    use super::*;
+
+    const ALICE: &str = "9dc4e4790da6e1f00285c493ba491bfda3c3cba0c4511ac60ddadd6e74cdc31c";
+    const BOB: &str = "09f15c13dc7e0ce57041ed7eefea6d9927d10d9c1cc8eb8348dff19a799baa1a";
+
+    fn tag(values: &[&str]) -> TagEntry {
+        TagEntry {
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    fn pubkey(hex: &str) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(hex).unwrap()
+    }
+
+    #[test]
+    fn test_reported_pubkeys_type_from_p_tag() {
+        let reported = reported_pubkeys(&[tag(&["p", ALICE, "spam"]), tag(&["p", BOB, "nudity"])]);
+
+        assert_eq!(reported.len(), 2);
+        assert_eq!(reported[&pubkey(ALICE)], "spam");
+        assert_eq!(reported[&pubkey(BOB)], "nudity");
+    }
+
+    #[test]
+    fn test_reported_pubkeys_type_from_e_tag() {
+        let reported = reported_pubkeys(&[
+            tag(&["e", "a1b2", "impersonation"]),
+            tag(&["p", ALICE]),
+            tag(&["p", BOB, ""]),
+        ]);
+
+        assert_eq!(reported[&pubkey(ALICE)], "impersonation");
+        assert_eq!(reported[&pubkey(BOB)], "impersonation");
+    }
+
+    #[test]
+    fn test_reported_pubkeys_type_defaults_to_other() {
+        let reported = reported_pubkeys(&[tag(&["e", "a1b2", ""]), tag(&["p", ALICE])]);
+
+        assert_eq!(reported[&pubkey(ALICE)], "other");
+    }
+
+    #[test]
+    fn test_reported_pubkeys_ignores_invalid_tags() {
+        let reported = reported_pubkeys(&[
+            tag(&["p", "not a pubkey", "spam"]),
+            tag(&["p"]),
+            tag(&["t", ALICE, "spam"]),
+            tag(&[]),
+        ]);
+
+        assert!(reported.is_empty());
+    }
+
+    fn event_with(id: Vec<u8>, tags: Vec<TagEntry>) -> Event {
+        Event {
+            id,
+            pubkey: vec![],
+            created_at: 0,
+            kind: 1,
+            content: String::new(),
+            tags,
+            sig: vec![],
+        }
+    }
+
+    /// 32 byte id with `zeros` leading zero bits
+    fn id_with_zeros(zeros: usize) -> Vec<u8> {
+        let mut id = vec![0xff; 32];
+        for i in 0..zeros {
+            id[i / 8] &= !(0x80 >> (i % 8));
+        }
+        id
+    }
+
+    #[test]
+    fn test_pow_difficulty_counts_leading_zero_bits() {
+        assert_eq!(
+            pow_difficulty(&event_with(id_with_zeros(0), vec![]), false),
+            0
+        );
+        assert_eq!(
+            pow_difficulty(&event_with(id_with_zeros(5), vec![]), false),
+            5
+        );
+        assert_eq!(
+            pow_difficulty(&event_with(id_with_zeros(20), vec![]), false),
+            20
+        );
+    }
+
+    #[test]
+    fn test_pow_difficulty_capped_by_nonce_target() {
+        let event = event_with(id_with_zeros(20), vec![tag(&["nonce", "776797", "16"])]);
+        assert_eq!(pow_difficulty(&event, true), 16);
+
+        let event = event_with(id_with_zeros(20), vec![tag(&["nonce", "776797", "24"])]);
+        assert_eq!(pow_difficulty(&event, true), 20);
+    }
+
+    #[test]
+    fn test_pow_difficulty_without_nonce_target() {
+        let event = event_with(id_with_zeros(20), vec![]);
+        assert_eq!(pow_difficulty(&event, true), 0);
+
+        let event = event_with(id_with_zeros(20), vec![tag(&["nonce", "776797"])]);
+        assert_eq!(pow_difficulty(&event, true), 0);
+
+        let event = event_with(id_with_zeros(20), vec![tag(&["nonce", "776797", "many"])]);
+        assert_eq!(pow_difficulty(&event, true), 0);
+        assert_eq!(pow_difficulty(&event, false), 20);
+    }
+
+    #[test]
+    fn test_parse_pubkey_formats() {
+        let alice = pubkey(ALICE);
+        let npub = alice.to_bech32().unwrap();
+        let nprofile = Profile::new(alice, vec!["wss://relay.example"])
+            .to_bech32()
+            .unwrap();
+
+        assert_eq!(parse_pubkey(ALICE).unwrap(), alice);
+        assert_eq!(parse_pubkey(&npub).unwrap(), alice);
+        assert_eq!(parse_pubkey(&format!(" nostr:{npub} ")).unwrap(), alice);
+        assert_eq!(parse_pubkey(&nprofile).unwrap(), alice);
+        assert!(parse_pubkey(&npub[..npub.len() - 1]).is_err());
+        assert!(parse_pubkey(&ALICE[1..]).is_err());
+        assert!(parse_pubkey("").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_pubkeys_rejects_invalid_entries() {
+        #[derive(Deserialize)]
+        struct Pubkeys {
+            #[serde(default, deserialize_with = "deserialize_optional_pubkeys")]
+            pubkeys: Option<HashSet<XOnlyPublicKey>>,
+        }
+
+        let npub = pubkey(BOB).to_bech32().unwrap();
+        let parsed: Pubkeys =
+            serde_json::from_str(&format!(r#"{{"pubkeys": ["{ALICE}", "{npub}"]}}"#)).unwrap();
+        assert_eq!(
+            parsed.pubkeys.unwrap(),
+            HashSet::from([pubkey(ALICE), pubkey(BOB)])
+        );
+
+        let parsed: Pubkeys = serde_json::from_str("{}").unwrap();
+        assert!(parsed.pubkeys.is_none());
+
+        let invalid = serde_json::from_str::<Pubkeys>(r#"{"pubkeys": ["npub1nope"]}"#);
+        assert!(invalid.is_err());
+    }
+}